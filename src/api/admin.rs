@@ -0,0 +1,492 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use axum::extract::{Path, Query, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use ipnet::IpNet;
+use ring::constant_time::verify_slices_are_equal;
+use serde::{Deserialize, Serialize};
+use toml_edit::ser::ValueSerializer;
+use toml_edit::{value, Array, ArrayOfTables, Item, Table, Value};
+use trust_dns_server::proto::rr::{LowerName, Name};
+use url::Url;
+
+use crate::api::ApiState;
+use crate::args::{Args, UpstreamDns};
+
+/// Routes requiring the API token as bearer token.
+pub(super) fn router(state: ApiState) -> Router<ApiState> {
+  Router::new()
+    .route("/blocklist", get(blocklist))
+    .route("/blocklist/sources", put(set_sources))
+    .route("/blocklist/allow", put(set_allow))
+    .route("/blocklist/deny", put(set_deny))
+    .route("/blocklist/refresh", post(refresh))
+    .route("/zones", get(zones))
+    .route("/zones/:zone", put(set_zone).delete(remove_zone))
+    .route("/client-groups", get(client_groups))
+    .route(
+      "/client-groups/:group",
+      put(set_client_group).delete(remove_client_group),
+    )
+    .route("/cache", delete(flush_cache))
+    .route("/reload", post(reload))
+    .route_layer(middleware::from_fn_with_state(state, authenticate))
+}
+
+/// Requires the API token as bearer token.
+pub(super) async fn authenticate<B>(
+  State(state): State<ApiState>,
+  request: Request<B>,
+  next: Next<B>,
+) -> Response {
+  let given = request
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|header| header.to_str().ok())
+    .and_then(|header| header.strip_prefix("Bearer "));
+
+  let authorized = match (&state.admin_token, given) {
+    (Some(token), Some(given)) => {
+      verify_slices_are_equal(token.as_bytes(), given.as_bytes()).is_ok()
+    }
+    _ => false,
+  };
+
+  if !authorized {
+    return (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")]).into_response();
+  }
+
+  next.run(request).await
+}
+
+/// Changes are kept until restart, or written to the config file with
+/// `?persist=true`.
+#[derive(Deserialize)]
+struct Persist {
+  #[serde(default)]
+  persist: bool,
+}
+
+#[derive(Serialize)]
+struct BlocklistResponse {
+  /// Empty if the built-in sources are used.
+  sources: Vec<String>,
+  allow: Vec<String>,
+  deny: Vec<String>,
+  size: usize,
+  loaded: bool,
+}
+
+async fn blocklist(State(state): State<ApiState>) -> Json<BlocklistResponse> {
+  let blacklist = state.blacklist.load();
+  let names = |names: &[LowerName]| names.iter().map(ToString::to_string).collect();
+
+  Json(BlocklistResponse {
+    sources: blacklist.sources().map(ToString::to_string).collect(),
+    allow: names(blacklist.allow()),
+    deny: names(blacklist.deny()),
+    size: blacklist.len(),
+    loaded: blacklist.is_loaded(),
+  })
+}
+
+/// Replaces the sources, an empty list restores the built-in ones. The
+/// blacklist is fetched again before the change is answered.
+async fn set_sources(
+  State(state): State<ApiState>,
+  Query(Persist { persist }): Query<Persist>,
+  Json(sources): Json<Vec<String>>,
+) -> Response {
+  let sources = match parse_all::<Url>(&sources) {
+    Ok(sources) => sources,
+    Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+  };
+
+  let edit = state.reloader.edit(
+    "blacklist_sources",
+    &["blocklist", "sources"],
+    persist,
+    |_, _| Ok(strings(&sources)),
+  );
+  respond(edit.await)
+}
+
+async fn set_allow(
+  State(state): State<ApiState>,
+  Query(Persist { persist }): Query<Persist>,
+  Json(names): Json<Vec<String>>,
+) -> Response {
+  set_names(state, "blacklist_allow", "allow", persist, names).await
+}
+
+async fn set_deny(
+  State(state): State<ApiState>,
+  Query(Persist { persist }): Query<Persist>,
+  Json(names): Json<Vec<String>>,
+) -> Response {
+  set_names(state, "blacklist_deny", "deny", persist, names).await
+}
+
+async fn set_names(
+  state: ApiState,
+  arg: &str,
+  key: &str,
+  persist: bool,
+  names: Vec<String>,
+) -> Response {
+  let names = match parse_all::<Name>(&names) {
+    Ok(names) => names,
+    Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+  };
+
+  let path = ["blocklist", key];
+  let edit = state
+    .reloader
+    .edit(arg, &path, persist, |_, _| Ok(strings(&names)));
+  respond(edit.await)
+}
+
+/// Fetches the lists of the current sources again.
+async fn refresh(State(state): State<ApiState>) -> Response {
+  match state.reloader.refresh_blacklist().await {
+    Ok(()) => StatusCode::NO_CONTENT.into_response(),
+    Err(err) => (StatusCode::BAD_GATEWAY, err.to_string()).into_response(),
+  }
+}
+
+#[derive(Serialize)]
+struct ZoneResponse {
+  zone: String,
+  upstreams: Vec<String>,
+}
+
+async fn zones(State(state): State<ApiState>) -> Json<Vec<ZoneResponse>> {
+  Json(
+    state
+      .zones
+      .load()
+      .upstreams()
+      .iter()
+      .map(|group| ZoneResponse {
+        zone: group.zone().to_string(),
+        upstreams: group.labels().map(str::to_string).collect(),
+      })
+      .collect(),
+  )
+}
+
+/// Upstreams as in the config file, either strings or tables.
+#[derive(Deserialize)]
+struct ZoneRequest {
+  upstreams: Vec<toml::Value>,
+}
+
+/// Adds the zone or replaces its upstreams.
+async fn set_zone(
+  State(state): State<ApiState>,
+  Path(zone): Path<String>,
+  Query(Persist { persist }): Query<Persist>,
+  Json(request): Json<ZoneRequest>,
+) -> Response {
+  let (zone, upstreams) = match parse_zone(&zone, &request.upstreams) {
+    Ok(parsed) => parsed,
+    Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+  };
+
+  let edit = state
+    .reloader
+    .edit("forwarding", &["forwarding"], persist, |current, args| {
+      let mut tables = forwarding(current, args)?;
+
+      match tables.iter_mut().find(|table| is_zone(table, &zone)) {
+        Some(table) => table["upstreams"] = value(upstreams),
+        None => {
+          let mut table = Table::new();
+          table["zone"] = value(zone.to_string());
+          table["upstreams"] = value(upstreams);
+          tables.push(table);
+        }
+      }
+
+      Ok(Item::ArrayOfTables(tables))
+    });
+  respond(edit.await)
+}
+
+async fn remove_zone(
+  State(state): State<ApiState>,
+  Path(zone): Path<String>,
+  Query(Persist { persist }): Query<Persist>,
+) -> Response {
+  let zone = match Name::from_str(&zone) {
+    Ok(zone) => zone,
+    Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+  };
+
+  let edit = state
+    .reloader
+    .edit("forwarding", &["forwarding"], persist, |current, args| {
+      let mut tables = forwarding(current, args)?;
+
+      let index = tables
+        .iter()
+        .position(|table| is_zone(table, &zone))
+        .ok_or_else(|| anyhow!("No forwarding zone {}", zone))?;
+      tables.remove(index);
+
+      Ok(Item::ArrayOfTables(tables))
+    });
+  respond(edit.await)
+}
+
+#[derive(Serialize)]
+struct ClientGroupResponse {
+  name: String,
+  clients: Vec<String>,
+  /// Whether the blocklist applies to the clients.
+  blocking: bool,
+}
+
+async fn client_groups(State(state): State<ApiState>) -> Json<Vec<ClientGroupResponse>> {
+  Json(
+    state
+      .client_groups
+      .load()
+      .iter()
+      .map(|group| ClientGroupResponse {
+        name: group.name().to_string(),
+        clients: group.networks().iter().map(ToString::to_string).collect(),
+        blocking: group.is_blocking(),
+      })
+      .collect(),
+  )
+}
+
+#[derive(Deserialize)]
+struct ClientGroupRequest {
+  clients: Vec<String>,
+}
+
+/// Adds the group or replaces its networks. Whether it bypasses the
+/// blocklist stays as configured.
+async fn set_client_group(
+  State(state): State<ApiState>,
+  Path(group): Path<String>,
+  Query(Persist { persist }): Query<Persist>,
+  Json(request): Json<ClientGroupRequest>,
+) -> Response {
+  let networks = match parse_all::<IpNet>(&request.clients) {
+    Ok(networks) if !networks.is_empty() => networks,
+    Ok(_) => {
+      let err = format!("Missing clients of group {}", group);
+      return (StatusCode::BAD_REQUEST, err).into_response();
+    }
+    Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+  };
+
+  let edit = state.reloader.edit(
+    "client_groups",
+    &["client_groups"],
+    persist,
+    |current, args| {
+      let mut tables = client_group_tables(current, args)?;
+
+      match tables.iter_mut().find(|table| is_group(table, &group)) {
+        Some(table) => table["clients"] = strings(&networks),
+        None => {
+          let mut table = Table::new();
+          table["name"] = value(group.as_str());
+          table["clients"] = strings(&networks);
+          tables.push(table);
+        }
+      }
+
+      Ok(Item::ArrayOfTables(tables))
+    },
+  );
+  respond(edit.await)
+}
+
+async fn remove_client_group(
+  State(state): State<ApiState>,
+  Path(group): Path<String>,
+  Query(Persist { persist }): Query<Persist>,
+) -> Response {
+  let edit = state.reloader.edit(
+    "client_groups",
+    &["client_groups"],
+    persist,
+    |current, args| {
+      let mut tables = client_group_tables(current, args)?;
+
+      let index = tables
+        .iter()
+        .position(|table| is_group(table, &group))
+        .ok_or_else(|| anyhow!("No client group {}", group))?;
+      tables.remove(index);
+
+      Ok(Item::ArrayOfTables(tables))
+    },
+  );
+  respond(edit.await)
+}
+
+#[derive(Deserialize)]
+struct FlushQuery {
+  /// All entries are dropped without a name.
+  name: Option<String>,
+  /// Also drop the entries of names below.
+  #[serde(default)]
+  subdomains: bool,
+}
+
+#[derive(Serialize)]
+struct FlushResponse {
+  flushed: usize,
+}
+
+async fn flush_cache(
+  State(state): State<ApiState>,
+  Query(query): Query<FlushQuery>,
+) -> Result<Json<FlushResponse>, (StatusCode, String)> {
+  let name = query
+    .name
+    .map(|name| Name::from_str(&name).map(LowerName::from))
+    .transpose()
+    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+  Ok(Json(FlushResponse {
+    flushed: state.cache.flush(name.as_ref(), query.subdomains),
+  }))
+}
+
+/// Reloads the configuration, as SIGHUP does. A configuration failing
+/// validation is rejected with the reason.
+async fn reload(State(state): State<ApiState>) -> Response {
+  respond(state.reloader.reload().await)
+}
+
+/// Changes are answered like reloads: rejected with the reason unless the
+/// resulting configuration is valid.
+fn respond(result: anyhow::Result<()>) -> Response {
+  match result {
+    Ok(()) => StatusCode::NO_CONTENT.into_response(),
+    Err(err) => (StatusCode::UNPROCESSABLE_ENTITY, err.to_string()).into_response(),
+  }
+}
+
+fn parse_all<T: FromStr>(values: &[String]) -> anyhow::Result<Vec<T>>
+where
+  T::Err: std::fmt::Display,
+{
+  values
+    .iter()
+    .map(|value| {
+      value
+        .parse()
+        .map_err(|err| anyhow!("Invalid value {:?}: {}", value, err))
+    })
+    .collect()
+}
+
+fn strings<T: ToString>(values: &[T]) -> Item {
+  value(Array::from_iter(values.iter().map(ToString::to_string)))
+}
+
+/// Validates the zone and its upstreams, converted for the config file.
+fn parse_zone(zone: &str, upstreams: &[toml::Value]) -> anyhow::Result<(Name, Array)> {
+  let zone = Name::from_str(zone)?;
+  if upstreams.is_empty() {
+    return Err(anyhow!("Missing upstreams of zone {}", zone));
+  }
+
+  let upstreams = upstreams
+    .iter()
+    .map(|upstream| {
+      UpstreamDns::deserialize(upstream.clone())?;
+      Ok(upstream.serialize(ValueSerializer::new())?)
+    })
+    .collect::<anyhow::Result<Vec<Value>>>()?;
+
+  Ok((zone, Array::from_iter(upstreams)))
+}
+
+/// The forwarding zones of the config file, the ones of the arguments if
+/// the file has none.
+fn forwarding(current: Option<Item>, args: &Args) -> anyhow::Result<ArrayOfTables> {
+  let mut tables = ArrayOfTables::new();
+
+  match current {
+    Some(Item::ArrayOfTables(current)) => return Ok(current),
+    Some(Item::Value(Value::Array(current))) => {
+      for zone in current {
+        match zone {
+          Value::InlineTable(zone) => tables.push(zone.into_table()),
+          _ => return Err(anyhow!("Invalid forwarding zone {}", zone)),
+        }
+      }
+    }
+    Some(_) => return Err(anyhow!("Invalid forwarding zones")),
+    // only the default, as explicit arguments can't be changed, whose
+    // upstreams have no TLS options lost by formatting them
+    None => {
+      for forwarding in &args.forwarding {
+        let mut table = Table::new();
+        table["zone"] = value(forwarding.name.to_string());
+        table["upstreams"] = value(Array::from_iter(
+          forwarding.upstreams.iter().map(ToString::to_string),
+        ));
+        tables.push(table);
+      }
+    }
+  }
+
+  Ok(tables)
+}
+
+/// The client groups of the config file, the ones of the arguments if the
+/// file has none.
+fn client_group_tables(current: Option<Item>, args: &Args) -> anyhow::Result<ArrayOfTables> {
+  let mut tables = ArrayOfTables::new();
+
+  match current {
+    Some(Item::ArrayOfTables(current)) => return Ok(current),
+    Some(Item::Value(Value::Array(current))) => {
+      for group in current {
+        match group {
+          Value::InlineTable(group) => tables.push(group.into_table()),
+          _ => return Err(anyhow!("Invalid client group {}", group)),
+        }
+      }
+    }
+    Some(_) => return Err(anyhow!("Invalid client groups")),
+    None => {
+      for group in &args.client_groups {
+        let mut table = Table::new();
+        table["name"] = value(group.name.as_str());
+        table["clients"] = strings(&group.networks);
+        tables.push(table);
+      }
+    }
+  }
+
+  Ok(tables)
+}
+
+fn is_group(table: &Table, name: &str) -> bool {
+  table.get("name").and_then(Item::as_str) == Some(name)
+}
+
+fn is_zone(table: &Table, zone: &Name) -> bool {
+  table
+    .get("zone")
+    .and_then(Item::as_str)
+    .and_then(|name| Name::from_str(name).ok())
+    .map_or(false, |name| name == *zone)
+}
@@ -0,0 +1,31 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+
+use crate::api::ApiState;
+use crate::readiness::Report;
+
+pub(super) fn router() -> Router<ApiState> {
+  Router::new()
+    .route("/healthz", get(healthz))
+    .route("/readyz", get(readyz))
+}
+
+/// Answers as long as the process is alive.
+async fn healthz() -> StatusCode {
+  StatusCode::OK
+}
+
+/// Reports every check, with 503 unless all of them pass. Checks run at
+/// most every few seconds, more frequent polls get the last report.
+async fn readyz(State(state): State<ApiState>) -> (StatusCode, Json<Report>) {
+  let report = state.readiness.check().await;
+  let status = if report.is_ready() {
+    StatusCode::OK
+  } else {
+    StatusCode::SERVICE_UNAVAILABLE
+  };
+
+  (status, Json(report))
+}
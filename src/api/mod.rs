@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use axum::{middleware, Router};
+use tokio::net::TcpListener;
+
+use crate::blacklist::Blacklist;
+use crate::cache::ResponseCache;
+use crate::client_groups::ClientGroups;
+use crate::readiness::Readiness;
+use crate::reload::{Reloader, Swap};
+use crate::shutdown::Shutdown;
+use crate::stats::Recorders;
+use crate::zones::Zones;
+
+mod admin;
+mod health;
+mod queries;
+mod stats;
+mod upstreams;
+
+#[derive(Clone)]
+pub(crate) struct ApiState {
+  pub(crate) recorders: Recorders,
+  pub(crate) blacklist: Swap<Blacklist>,
+  pub(crate) client_groups: Swap<ClientGroups>,
+  pub(crate) cache: Arc<ResponseCache>,
+  pub(crate) zones: Swap<Zones>,
+  pub(crate) reloader: Arc<Reloader>,
+  pub(crate) readiness: Arc<Readiness>,
+  /// Token of the admin API, which is disabled without.
+  pub(crate) admin_token: Option<Arc<str>>,
+}
+
+pub(crate) fn router(state: ApiState) -> Router {
+  // the recorded queries reveal clients and names, so once there is a
+  // token they require it
+  let mut recorded = Router::new()
+    .nest("/api/queries", queries::router())
+    .nest("/api/stats", stats::router());
+
+  let mut router = Router::new()
+    .merge(health::router())
+    .nest("/api/upstreams", upstreams::router());
+
+  if state.admin_token.is_some() {
+    recorded = recorded.route_layer(middleware::from_fn_with_state(
+      state.clone(),
+      admin::authenticate,
+    ));
+    router = router.nest("/api/admin", admin::router(state.clone()));
+  }
+  router = router.merge(recorded);
+
+  router.with_state(state)
+}
+
+pub(crate) async fn serve(
+  listener: TcpListener,
+  router: Router,
+  shutdown: Shutdown,
+) -> anyhow::Result<()> {
+  axum::Server::from_tcp(listener.into_std()?)?
+    .serve(router.into_make_service())
+    .with_graceful_shutdown(async move { shutdown.triggered().await })
+    .await?;
+
+  Ok(())
+}
@@ -0,0 +1,65 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive};
+use axum::response::Sse;
+use axum::routing::get;
+use axum::{Json, Router};
+use futures_util::stream::{self, Stream};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::api::ApiState;
+use crate::stats::query_log::{Filter, LoggedQuery, QueryLog};
+
+const DEFAULT_LIMIT: usize = 100;
+
+pub(super) fn router() -> Router<ApiState> {
+  Router::new()
+    .route("/", get(search))
+    .route("/tail", get(tail))
+}
+
+fn query_log(state: &ApiState) -> Result<&Arc<QueryLog>, StatusCode> {
+  state
+    .recorders
+    .query_log
+    .as_ref()
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn search(
+  State(state): State<ApiState>,
+  Query(filter): Query<Filter>,
+) -> Result<Json<Vec<LoggedQuery>>, StatusCode> {
+  let limit = filter.limit.unwrap_or(DEFAULT_LIMIT);
+  Ok(Json(query_log(&state)?.search(&filter, limit)))
+}
+
+/// Streams matching queries as server-sent events as they happen. Events
+/// a slow client can't keep up with are skipped.
+async fn tail(
+  State(state): State<ApiState>,
+  Query(filter): Query<Filter>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+  let receiver = query_log(&state)?.subscribe();
+
+  let stream = stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+    loop {
+      match receiver.recv().await {
+        Ok(query) if filter.matches(&query) => {
+          let event = Event::default()
+            .json_data(&query)
+            .unwrap_or_else(|_| Event::default().comment("unserializable query"));
+          return Some((Ok(event), (receiver, filter)));
+        }
+        Ok(_) | Err(RecvError::Lagged(_)) => continue,
+        Err(RecvError::Closed) => return None,
+      }
+    }
+  });
+
+  Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(Duration::from_secs(15))))
+}
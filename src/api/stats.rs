@@ -0,0 +1,63 @@
+use axum::extract::{Path, Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+
+use crate::api::ApiState;
+use crate::cache::CacheStats;
+use crate::stats::aggregate::{Point, TopEntry, TopList};
+
+const DEFAULT_LIMIT: usize = 10;
+
+pub(super) fn router() -> Router<ApiState> {
+  Router::new()
+    .route("/summary", get(summary))
+    .route("/timeseries", get(timeseries))
+    .route("/top/:list", get(top))
+    .route("/cache", get(cache))
+}
+
+#[derive(Serialize)]
+struct SummaryResponse {
+  queries: u64,
+  blocked: u64,
+  avg_duration_us: u64,
+  blocklist_size: usize,
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+  limit: Option<usize>,
+}
+
+async fn summary(State(state): State<ApiState>) -> Json<SummaryResponse> {
+  let summary = state.recorders.aggregates.summary();
+
+  Json(SummaryResponse {
+    queries: summary.queries,
+    blocked: summary.blocked,
+    avg_duration_us: summary.avg_duration_us,
+    blocklist_size: state.blacklist.load().len(),
+  })
+}
+
+async fn timeseries(State(state): State<ApiState>) -> Json<Vec<Point>> {
+  Json(state.recorders.aggregates.timeseries())
+}
+
+async fn top(
+  State(state): State<ApiState>,
+  Path(list): Path<TopList>,
+  Query(query): Query<TopQuery>,
+) -> Json<Vec<TopEntry>> {
+  Json(
+    state
+      .recorders
+      .aggregates
+      .top(list, query.limit.unwrap_or(DEFAULT_LIMIT)),
+  )
+}
+
+async fn cache(State(state): State<ApiState>) -> Json<CacheStats> {
+  Json(state.cache.stats())
+}
@@ -0,0 +1,22 @@
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+
+use crate::api::ApiState;
+use crate::upstream::GroupStats;
+
+pub(super) fn router() -> Router<ApiState> {
+  Router::new().route("/", get(upstreams))
+}
+
+async fn upstreams(State(state): State<ApiState>) -> Json<Vec<GroupStats>> {
+  Json(
+    state
+      .zones
+      .load()
+      .upstreams()
+      .iter()
+      .map(|group| group.stats())
+      .collect(),
+  )
+}
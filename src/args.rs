@@ -1,13 +1,34 @@
+use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::anyhow;
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, FromArgMatches, Parser, Subcommand};
+use ipnet::IpNet;
 use trust_dns_server::resolver::Name;
-use url::Url;
+use url::{Host, Url};
+
+use crate::config::{Config, Edits};
+use crate::logging::{LogFormat, LogTarget};
+use crate::privacy::ClientPrivacy;
+use crate::stats::buffer::OverflowPolicy;
+use crate::stats::influx::{InfluxConfig, InfluxVersion, WritePrecision};
+use crate::upstream::health::HealthConfig;
+use crate::upstream::tls::TlsOptions;
+use crate::upstream::{Strategy, UpstreamConfig};
 
 #[derive(Parser)]
 pub(super) struct Args {
+  #[command(subcommand)]
+  pub(super) command: Option<Command>,
+  /// TOML file with the settings not given as argument or environment
+  /// variable.
+  #[arg(long, env = "RDNS_CONFIG", global = true)]
+  pub(super) config: Option<PathBuf>,
+
   #[arg(
     short,
     long,
@@ -22,6 +43,51 @@ pub(super) struct Args {
     default_value = "0.0.0.0:53"
   )]
   pub(super) tcp_listen_addr: Vec<SocketAddr>,
+  /// Addresses of the DNS over TLS listeners, usually on port 853.
+  #[arg(long, env = "RDNS_TLS_LISTEN_ADDR", value_delimiter = ',')]
+  pub(super) tls_listen_addr: Vec<SocketAddr>,
+  /// PEM certificate chain of the TLS listeners.
+  #[arg(long, env = "RDNS_TLS_CERT")]
+  pub(super) tls_cert: Option<PathBuf>,
+  /// PEM private key of the TLS listeners.
+  #[arg(long, env = "RDNS_TLS_KEY")]
+  pub(super) tls_key: Option<PathBuf>,
+  /// Interval in seconds to check certificate and key for changes, 0
+  /// disables reloading.
+  #[arg(long, env = "RDNS_TLS_RELOAD_INTERVAL", default_value_t = 60)]
+  pub(super) tls_reload_interval: u64,
+  /// Addresses of the DNS over QUIC listeners, usually on port 853.
+  #[arg(long, env = "RDNS_QUIC_LISTEN_ADDR", value_delimiter = ',')]
+  pub(super) quic_listen_addr: Vec<SocketAddr>,
+  /// Addresses of the DNS over HTTPS listeners, usually on port 443.
+  #[arg(long, env = "RDNS_HTTPS_LISTEN_ADDR", value_delimiter = ',')]
+  pub(super) https_listen_addr: Vec<SocketAddr>,
+  /// Addresses of plain HTTP DNS over HTTPS listeners for a reverse proxy
+  /// terminating TLS.
+  #[arg(long, env = "RDNS_HTTP_LISTEN_ADDR", value_delimiter = ',')]
+  pub(super) http_listen_addr: Vec<SocketAddr>,
+  /// Expect a PROXY protocol header from trusted proxies on HTTP listeners.
+  #[arg(long, env = "RDNS_HTTP_PROXY_PROTOCOL")]
+  pub(super) http_proxy_protocol: bool,
+  /// Expect a PROXY protocol header from trusted proxies on UDP listeners.
+  #[arg(long, env = "RDNS_UDP_PROXY_PROTOCOL")]
+  pub(super) udp_proxy_protocol: bool,
+  /// Expect a PROXY protocol header from trusted proxies on TCP listeners.
+  #[arg(long, env = "RDNS_TCP_PROXY_PROTOCOL")]
+  pub(super) tcp_proxy_protocol: bool,
+  /// Expect a PROXY protocol header from trusted proxies on TLS listeners.
+  #[arg(long, env = "RDNS_TLS_PROXY_PROTOCOL")]
+  pub(super) tls_proxy_protocol: bool,
+  /// Networks of proxies trusted to pass the address of the client, by
+  /// forwarding headers or the PROXY protocol.
+  #[arg(long, env = "RDNS_TRUSTED_PROXIES", value_delimiter = ',')]
+  pub(super) trusted_proxies: Vec<IpNet>,
+  /// Path of the DNS over HTTPS endpoint.
+  #[arg(long, env = "RDNS_DOH_PATH", default_value = "/dns-query", value_parser = parse_path)]
+  pub(super) doh_path: String,
+  /// Answer `application/dns-json` queries like `?name=example.com&type=A`.
+  #[arg(long, env = "RDNS_DOH_JSON")]
+  pub(super) doh_json: bool,
 
   #[arg(
     long,
@@ -44,18 +110,357 @@ pub(super) struct Args {
   default_value = ".:https:1.1.1.2:443/security.cloudflare-dns.com,https:1.0.0.2:443/security.cloudflare-dns.com,https:[2606:4700:4700::1112]:443/security.cloudflare-dns.com,https:[2606:4700:4700::1002]:443/security.cloudflare-dns.com"
   )]
   pub(super) forwarding: Vec<Forwarding>,
+  /// Resolvers used to look up upstreams addressed by hostname.
+  #[arg(
+    long,
+    env = "RDNS_BOOTSTRAP_RESOLVER",
+    value_delimiter = ',',
+    default_value = "1.1.1.2:53,1.0.0.2:53"
+  )]
+  pub(super) bootstrap_resolver: Vec<SocketAddr>,
+  /// Seconds after which upstreams addressed by hostname are resolved again,
+  /// 0 resolves them only once.
+  #[arg(long, env = "RDNS_BOOTSTRAP_REFRESH", default_value_t = 300)]
+  pub(super) bootstrap_refresh: u64,
+  /// How the upstreams of a zone are chosen.
+  #[arg(
+    long,
+    env = "RDNS_UPSTREAM_STRATEGY",
+    value_enum,
+    default_value_t = Strategy::LowestLatency
+  )]
+  pub(super) upstream_strategy: Strategy,
+  /// Milliseconds to wait for a single upstream to answer.
+  #[arg(long, env = "RDNS_UPSTREAM_TIMEOUT", default_value_t = 2000)]
+  pub(super) upstream_timeout: u64,
+  /// Seconds between two health probes of each upstream, 0 disables probing.
+  #[arg(long, env = "RDNS_UPSTREAM_HEALTH_INTERVAL", default_value_t = 10)]
+  pub(super) upstream_health_interval: u64,
+  /// Consecutive failures after which an upstream is taken out of rotation.
+  #[arg(long, env = "RDNS_UPSTREAM_FAILURE_THRESHOLD", default_value_t = 3)]
+  pub(super) upstream_failure_threshold: u32,
+  /// Seconds a failed upstream stays out of rotation before it is tried again.
+  #[arg(long, env = "RDNS_UPSTREAM_CIRCUIT_OPEN", default_value_t = 30)]
+  pub(super) upstream_circuit_open: u64,
+
+  /// Approximate memory in bytes used to cache responses, 0 disables the cache.
+  #[arg(long, env = "RDNS_CACHE_MAX_MEMORY", default_value_t = 32 * 1024 * 1024)]
+  pub(super) cache_max_memory: usize,
+  /// Minimum seconds a response is cached, regardless of its TTL.
+  #[arg(long, env = "RDNS_CACHE_MIN_TTL", default_value_t = 0)]
+  pub(super) cache_min_ttl: u64,
+  #[arg(long, env = "RDNS_CACHE_MAX_TTL", default_value_t = 86400)]
+  pub(super) cache_max_ttl: u64,
+  /// Maximum seconds NXDOMAIN and NODATA responses are cached.
+  #[arg(long, env = "RDNS_CACHE_NEGATIVE_MAX_TTL", default_value_t = 3600)]
+  pub(super) cache_negative_max_ttl: u64,
+  /// Zones whose responses are never cached, e.g. `in-addr.arpa.`.
+  #[arg(long, env = "RDNS_CACHE_BYPASS", num_args(0..), value_delimiter = ',')]
+  pub(super) cache_bypass: Vec<Name>,
+  /// Seconds expired responses are served while upstreams fail, 0 disables
+  /// serving stale responses.
+  #[arg(long, env = "RDNS_CACHE_SERVE_STALE", default_value_t = 86400)]
+  pub(super) cache_serve_stale: u64,
+  #[arg(long, env = "RDNS_CACHE_STALE_TTL", default_value_t = 30)]
+  pub(super) cache_stale_ttl: u64,
+  /// Milliseconds to wait for upstreams before answering with a stale response.
+  #[arg(long, env = "RDNS_CACHE_STALE_TIMEOUT", default_value_t = 1800)]
+  pub(super) cache_stale_timeout: u64,
+  /// Hits after which a response is refreshed before it expires, 0 disables
+  /// prefetching.
+  #[arg(long, env = "RDNS_CACHE_PREFETCH_MIN_HITS", default_value_t = 5)]
+  pub(super) cache_prefetch_min_hits: u64,
+  /// Remaining percentage of the TTL at which popular responses are refreshed.
+  #[arg(
+    long,
+    env = "RDNS_CACHE_PREFETCH_THRESHOLD",
+    default_value_t = 10,
+    value_parser = clap::value_parser!(u8).range(1..=100)
+  )]
+  pub(super) cache_prefetch_threshold: u8,
+
+  /// Address of the HTTP API, disabled if not set.
+  #[arg(long, env = "RDNS_API_LISTEN_ADDR")]
+  pub(super) api_listen_addr: Option<SocketAddr>,
+  /// Bearer token of the admin API under `/api/admin`, which is disabled if
+  /// not set. Once set, `/api/queries` and `/api/stats` require it as well.
+  #[arg(long, env = "RDNS_API_TOKEN")]
+  pub(super) api_token: Option<String>,
+  /// Name queried through the first UDP listener on readiness checks, so
+  /// `/readyz` fails unless queries are answered.
+  #[arg(long, env = "RDNS_READINESS_PROBE")]
+  pub(super) readiness_probe: Option<Name>,
+
+  /// Log filter in the syntax of `RUST_LOG`, which isn't read itself, e.g.
+  /// `info,rdns::stats=debug`.
+  #[arg(long, env = "RDNS_LOG", default_value = "info")]
+  pub(super) log_filter: String,
+  #[arg(
+    long,
+    env = "RDNS_LOG_FORMAT",
+    value_enum,
+    default_value_t = LogFormat::Compact
+  )]
+  pub(super) log_format: LogFormat,
+  #[arg(
+    long,
+    env = "RDNS_LOG_TARGET",
+    value_enum,
+    default_value_t = LogTarget::Stdout
+  )]
+  pub(super) log_target: LogTarget,
+
+  /// OTLP/gRPC collector to export request spans to, e.g. `http://localhost:4317`.
+  #[arg(long, env = "RDNS_OTLP_ENDPOINT")]
+  pub(super) otlp_endpoint: Option<String>,
+  /// Share of requests to trace, between 0 and 1.
+  #[arg(long, env = "RDNS_OTLP_SAMPLE_RATIO", default_value_t = 1.0)]
+  pub(super) otlp_sample_ratio: f64,
+  /// Filter of the exported spans, independent of the log filter.
+  #[arg(long, env = "RDNS_OTLP_FILTER", default_value = "info")]
+  pub(super) otlp_filter: String,
 
   #[arg(short, long, env = "RDNS_BLACKLIST")]
   pub(super) blacklist: bool,
+  /// Lists of names to block, the built-in lists if not given.
+  #[arg(long, env = "RDNS_BLACKLIST_SOURCES", value_delimiter = ',')]
+  pub(super) blacklist_sources: Vec<Url>,
+  /// Names never blocked, including their subdomains.
+  #[arg(long, env = "RDNS_BLACKLIST_ALLOW", value_delimiter = ',')]
+  pub(super) blacklist_allow: Vec<Name>,
+  /// Names always blocked, including their subdomains.
+  #[arg(long, env = "RDNS_BLACKLIST_DENY", value_delimiter = ',')]
+  pub(super) blacklist_deny: Vec<Name>,
+  /// Client groups whose queries are never blocked.
+  #[arg(long, env = "RDNS_BLACKLIST_BYPASS", value_delimiter = ',')]
+  pub(super) blacklist_bypass: Vec<String>,
+  /// Named groups of client networks, e.g. `kids=10.0.1.0/24,2001:db8:1::/48`.
+  /// Queries are attributed to the first group containing the client.
+  #[arg(long = "client-group", env = "RDNS_CLIENT_GROUPS", num_args(0..))]
+  pub(super) client_groups: Vec<ClientGroupArg>,
 
-  #[arg(long, env = "RDNS_STATS_URL", requires = "stats_token")]
+  #[arg(long, env = "RDNS_STATS_URL")]
   pub(crate) stats_url: Option<Url>,
-  #[arg(long, env = "RDNS_STATS_TOKEN", requires = "stats_bucket")]
+  #[arg(
+    long,
+    env = "RDNS_STATS_VERSION",
+    value_enum,
+    default_value_t = InfluxVersion::V2
+  )]
+  pub(crate) stats_version: InfluxVersion,
+  #[arg(
+    long,
+    env = "RDNS_STATS_PRECISION",
+    value_enum,
+    default_value_t = WritePrecision::Milliseconds
+  )]
+  pub(crate) stats_precision: WritePrecision,
+  /// API token (v2 and v3).
+  #[arg(long, env = "RDNS_STATS_TOKEN")]
   pub(crate) stats_token: Option<String>,
-  #[arg(long, env = "RDNS_STATS_BUCKET", requires = "stats_org")]
+  /// Bucket to write to (v2).
+  #[arg(long, env = "RDNS_STATS_BUCKET")]
   pub(crate) stats_bucket: Option<String>,
-  #[arg(long, env = "RDNS_STATS_ORG", requires = "stats_url")]
+  /// Organization owning the bucket (v2).
+  #[arg(long, env = "RDNS_STATS_ORG")]
   pub(crate) stats_org: Option<String>,
+  /// Database to write to (v1 and v3).
+  #[arg(long, env = "RDNS_STATS_DATABASE")]
+  pub(crate) stats_database: Option<String>,
+  /// Basic auth username (v1).
+  #[arg(long, env = "RDNS_STATS_USERNAME")]
+  pub(crate) stats_username: Option<String>,
+  /// Basic auth password (v1).
+  #[arg(long, env = "RDNS_STATS_PASSWORD", requires = "stats_username")]
+  pub(crate) stats_password: Option<String>,
+  #[arg(long, env = "RDNS_STATS_BUFFER_CAPACITY", default_value_t = 16384)]
+  pub(crate) stats_buffer_capacity: usize,
+  #[arg(long, env = "RDNS_STATS_BATCH_SIZE", default_value_t = 1024)]
+  pub(crate) stats_batch_size: usize,
+  #[arg(
+    long,
+    env = "RDNS_STATS_OVERFLOW_POLICY",
+    value_enum,
+    default_value_t = OverflowPolicy::DropOldest
+  )]
+  pub(crate) stats_overflow_policy: OverflowPolicy,
+  /// Keep every n-th entry while the buffer is full (sample policy only).
+  #[arg(long, env = "RDNS_STATS_SAMPLE_RATE", default_value_t = 10)]
+  pub(crate) stats_sample_rate: u64,
+  /// Seconds between two flushes.
+  #[arg(long, env = "RDNS_STATS_FLUSH_INTERVAL", default_value_t = 10)]
+  pub(crate) stats_flush_interval: u64,
+  #[arg(long, env = "RDNS_STATS_MAX_RETRIES", default_value_t = 5)]
+  pub(crate) stats_max_retries: u32,
+  /// Milliseconds before the first retry, doubled on every further attempt.
+  #[arg(long, env = "RDNS_STATS_INITIAL_BACKOFF", default_value_t = 500)]
+  pub(crate) stats_initial_backoff: u64,
+  /// Upper bound of the retry backoff in milliseconds.
+  #[arg(long, env = "RDNS_STATS_MAX_BACKOFF", default_value_t = 30000)]
+  pub(crate) stats_max_backoff: u64,
+  /// Directory to keep batches in while the stats sink is unavailable.
+  #[arg(long, env = "RDNS_STATS_SPOOL_DIR")]
+  pub(crate) stats_spool_dir: Option<PathBuf>,
+  /// Size cap of the spool in bytes, the oldest batches are evicted first.
+  #[arg(
+    long,
+    env = "RDNS_STATS_SPOOL_MAX_SIZE",
+    default_value_t = 256 * 1024 * 1024
+  )]
+  pub(crate) stats_spool_max_size: u64,
+  /// Names kept per hour for the top lists of the stats API.
+  #[arg(long, env = "RDNS_STATS_TOP_CAPACITY", default_value_t = 1000)]
+  pub(crate) stats_top_capacity: usize,
+
+  /// Recent queries kept for the query log API, 0 disables the log.
+  #[arg(long, env = "RDNS_QUERY_LOG_CAPACITY", default_value_t = 10000)]
+  pub(crate) query_log_capacity: usize,
+  /// Upper bound of the memory used by the query log in bytes.
+  #[arg(
+    long,
+    env = "RDNS_QUERY_LOG_MAX_MEMORY",
+    default_value_t = 16 * 1024 * 1024
+  )]
+  pub(crate) query_log_max_memory: usize,
+
+  /// Resolve client names through NetBox (requires the NetBox options).
+  #[arg(
+    long,
+    env = "RDNS_CLIENT_NAMES_NETBOX",
+    requires = "reverse_dns_netbox_url"
+  )]
+  pub(crate) client_names_netbox: bool,
+  /// dnsmasq leases file to resolve client names from.
+  #[arg(long, env = "RDNS_CLIENT_NAMES_LEASES")]
+  pub(crate) client_names_leases: Option<PathBuf>,
+  /// DNS server (usually rdns itself) to resolve client names by PTR lookups.
+  #[arg(long, env = "RDNS_CLIENT_NAMES_RESOLVER")]
+  pub(crate) client_names_resolver: Option<SocketAddr>,
+  /// Seconds a resolved client name is cached.
+  #[arg(long, env = "RDNS_CLIENT_NAMES_TTL", default_value_t = 3600)]
+  pub(crate) client_names_ttl: u64,
+  /// Seconds a client without name is cached.
+  #[arg(long, env = "RDNS_CLIENT_NAMES_NEGATIVE_TTL", default_value_t = 300)]
+  pub(crate) client_names_negative_ttl: u64,
+  #[arg(long, env = "RDNS_CLIENT_NAMES_CAPACITY", default_value_t = 4096)]
+  pub(crate) client_names_capacity: usize,
+
+  /// How client addresses are recorded in stats and logs.
+  #[arg(
+    long,
+    env = "RDNS_PRIVACY_CLIENT",
+    value_enum,
+    default_value_t = ClientPrivacy::Full
+  )]
+  pub(crate) privacy_client: ClientPrivacy,
+  #[arg(
+    long,
+    env = "RDNS_PRIVACY_IPV4_PREFIX",
+    default_value_t = 24,
+    value_parser = clap::value_parser!(u8).range(0..=32)
+  )]
+  pub(crate) privacy_ipv4_prefix: u8,
+  #[arg(
+    long,
+    env = "RDNS_PRIVACY_IPV6_PREFIX",
+    default_value_t = 48,
+    value_parser = clap::value_parser!(u8).range(0..=128)
+  )]
+  pub(crate) privacy_ipv6_prefix: u8,
+  /// Seconds after which the key used to hash client addresses is replaced.
+  #[arg(long, env = "RDNS_PRIVACY_SALT_ROTATION", default_value_t = 86400)]
+  pub(crate) privacy_salt_rotation: u64,
+  /// Only record blocked queries.
+  #[arg(long, env = "RDNS_PRIVACY_ONLY_BLOCKED")]
+  pub(crate) privacy_only_blocked: bool,
+  /// Don't record query names.
+  #[arg(long, env = "RDNS_PRIVACY_OMIT_QUERY")]
+  pub(crate) privacy_omit_query: bool,
+
+  /// Seconds to wait on shutdown for the requests in flight and the final
+  /// stats flush.
+  #[arg(long, env = "RDNS_SHUTDOWN_TIMEOUT", default_value_t = 15)]
+  pub(crate) shutdown_timeout: u64,
+}
+
+#[derive(Subcommand)]
+pub(super) enum Command {
+  /// Validates arguments and config file, then exits.
+  CheckConfig,
+}
+
+impl Args {
+  /// Arguments of the command line and environment, completed by the
+  /// config file with the edits on top if one is given. The file is read
+  /// again on every call.
+  pub(super) fn from_matches(matches: &ArgMatches, edits: &Edits) -> anyhow::Result<Self> {
+    let mut args = Self::from_arg_matches(matches)?;
+
+    if let Some(path) = &args.config {
+      Config::load(path, edits)?.apply(&mut args, matches);
+    }
+
+    Ok(args)
+  }
+
+  pub(super) fn upstream_config(&self) -> UpstreamConfig {
+    UpstreamConfig {
+      strategy: self.upstream_strategy,
+      timeout: Duration::from_millis(self.upstream_timeout),
+      health: HealthConfig {
+        failure_threshold: self.upstream_failure_threshold,
+        open_duration: Duration::from_secs(self.upstream_circuit_open),
+      },
+    }
+  }
+
+  /// Settings of the stats sink, `None` if no sink is configured.
+  pub(super) fn influx_config(&self) -> Option<InfluxConfig> {
+    Some(InfluxConfig {
+      version: self.stats_version,
+      url: self.stats_url.clone()?,
+      precision: self.stats_precision,
+      token: self.stats_token.clone(),
+      org: self.stats_org.clone(),
+      bucket: self.stats_bucket.clone(),
+      database: self.stats_database.clone(),
+      username: self.stats_username.clone(),
+      password: self.stats_password.clone(),
+    })
+  }
+}
+
+/// Whether the argument was given on the command line or as environment
+/// variable, which take precedence over the config file.
+pub(super) fn is_explicit(matches: &ArgMatches, id: &str) -> bool {
+  matches!(
+    matches.value_source(id),
+    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+  )
+}
+
+#[derive(Clone)]
+pub(super) struct ClientGroupArg {
+  pub(super) name: String,
+  pub(super) networks: Vec<IpNet>,
+}
+
+impl FromStr for ClientGroupArg {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (name, networks) = s
+      .split_once('=')
+      .ok_or_else(|| anyhow!("Missing delimiter \"=\" to split group and networks."))?;
+
+    Ok(ClientGroupArg {
+      name: name.to_string(),
+      networks: networks
+        .split(',')
+        .map(IpNet::from_str)
+        .collect::<Result<_, _>>()?,
+    })
+  }
 }
 
 #[derive(Clone)]
@@ -68,8 +473,36 @@ pub(super) struct Forwarding {
 pub(super) enum UpstreamDns {
   Tcp(SocketAddr),
   Udp(SocketAddr),
-  Tls(SocketAddr, String),
-  Https(SocketAddr, String),
+  Tls(SocketAddr, String, TlsOptions),
+  Https(SocketAddr, String, TlsOptions),
+  /// DNS over QUIC (RFC 9250).
+  Quic(SocketAddr, String, TlsOptions),
+  /// DNS over HTTP/3.
+  H3(SocketAddr, String, TlsOptions),
+  /// DNS over TLS by hostname, e.g. `tls://dns.example:853`.
+  TlsHost(String, u16, TlsOptions),
+  /// DNS over HTTPS by URL, e.g. `https://dns.example/dns-query`.
+  HttpsUrl(Url, TlsOptions),
+  /// DNS over QUIC by hostname, e.g. `quic://dns.example:853`.
+  QuicHost(String, u16, TlsOptions),
+  /// DNS over HTTP/3 by URL, e.g. `h3://dns.example/dns-query`.
+  H3Url(Url, TlsOptions),
+}
+
+impl Display for UpstreamDns {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      UpstreamDns::Tcp(addr) => write!(f, "tcp:{}", addr),
+      UpstreamDns::Udp(addr) => write!(f, "udp:{}", addr),
+      UpstreamDns::Tls(addr, domain, _) => write!(f, "tls:{}/{}", addr, domain),
+      UpstreamDns::Https(addr, domain, _) => write!(f, "https:{}/{}", addr, domain),
+      UpstreamDns::Quic(addr, domain, _) => write!(f, "quic:{}/{}", addr, domain),
+      UpstreamDns::H3(addr, domain, _) => write!(f, "h3:{}/{}", addr, domain),
+      UpstreamDns::TlsHost(host, port, _) => write!(f, "tls://{}:{}", host, port),
+      UpstreamDns::HttpsUrl(url, _) | UpstreamDns::H3Url(url, _) => write!(f, "{}", url),
+      UpstreamDns::QuicHost(host, port, _) => write!(f, "quic://{}:{}", host, port),
+    }
+  }
 }
 
 impl FromStr for Forwarding {
@@ -80,36 +513,10 @@ impl FromStr for Forwarding {
       .split_once(':')
       .ok_or_else(|| anyhow!("Missing delimiter \":\" to split zone and upstream."))?;
 
-    let mut upstreams = Vec::new();
-
-    for raw in raw_upstreams.split(',') {
-      let (protocol, host) = raw
-        .split_once(':')
-        .ok_or_else(|| anyhow!("Invalid upstream format"))?;
-
-      match protocol {
-        "tcp" => upstreams.push(UpstreamDns::Tcp(host.parse()?)),
-        "udp" => upstreams.push(UpstreamDns::Udp(host.parse()?)),
-        "tls" => {
-          let (addr, domain) = host
-            .split_once('/')
-            .ok_or_else(|| anyhow!("Missing domain name for tls upstream"))?;
-          upstreams.push(UpstreamDns::Tls(addr.parse()?, domain.to_string()))
-        }
-        "https" => {
-          let (addr, domain) = host
-            .split_once('/')
-            .ok_or_else(|| anyhow!("Missing domain name for https upstream"))?;
-          upstreams.push(UpstreamDns::Https(addr.parse()?, domain.to_string()))
-        }
-        unknown => {
-          return Err(anyhow!(
-            "Invalid upstream protocol {}, allowed: [tcp, udp, tls, https]",
-            unknown
-          ));
-        }
-      }
-    }
+    let upstreams = raw_upstreams
+      .split(',')
+      .map(UpstreamDns::from_str)
+      .collect::<anyhow::Result<_>>()?;
 
     Ok(Forwarding {
       name: Name::from_str(name)?,
@@ -117,3 +524,152 @@ impl FromStr for Forwarding {
     })
   }
 }
+
+impl FromStr for UpstreamDns {
+  type Err = anyhow::Error;
+
+  fn from_str(raw: &str) -> Result<Self, Self::Err> {
+    let (protocol, host) = raw
+      .split_once(':')
+      .ok_or_else(|| anyhow!("Invalid upstream format"))?;
+
+    if host.starts_with("//") {
+      return parse_url_upstream(raw);
+    }
+
+    match protocol {
+      "tcp" => Ok(UpstreamDns::Tcp(host.parse()?)),
+      "udp" => Ok(UpstreamDns::Udp(host.parse()?)),
+      "tls" => {
+        let (addr, domain, options) = parse_encrypted_upstream(protocol, host)?;
+        Ok(UpstreamDns::Tls(addr, domain, options))
+      }
+      "https" => {
+        let (addr, domain, options) = parse_encrypted_upstream(protocol, host)?;
+        Ok(UpstreamDns::Https(addr, domain, options))
+      }
+      "quic" => {
+        let (addr, domain, options) = parse_encrypted_upstream(protocol, host)?;
+        Ok(UpstreamDns::Quic(addr, domain, options))
+      }
+      "h3" => {
+        let (addr, domain, options) = parse_encrypted_upstream(protocol, host)?;
+        Ok(UpstreamDns::H3(addr, domain, options))
+      }
+      unknown => Err(anyhow!(
+        "Invalid upstream protocol {}, allowed: [tcp, udp, tls, https, quic, h3]",
+        unknown
+      )),
+    }
+  }
+}
+
+impl UpstreamDns {
+  /// TLS options of encrypted upstreams, `None` for plain TCP and UDP.
+  pub(super) fn tls_options_mut(&mut self) -> Option<&mut TlsOptions> {
+    match self {
+      UpstreamDns::Tcp(_) | UpstreamDns::Udp(_) => None,
+      UpstreamDns::Tls(_, _, options)
+      | UpstreamDns::Https(_, _, options)
+      | UpstreamDns::Quic(_, _, options)
+      | UpstreamDns::H3(_, _, options)
+      | UpstreamDns::TlsHost(_, _, options)
+      | UpstreamDns::HttpsUrl(_, options)
+      | UpstreamDns::QuicHost(_, _, options)
+      | UpstreamDns::H3Url(_, options) => Some(options),
+    }
+  }
+}
+
+/// Upstreams addressed by hostname, resolved by the bootstrap resolvers.
+fn parse_url_upstream(raw: &str) -> anyhow::Result<UpstreamDns> {
+  let mut url = Url::parse(raw)?;
+
+  // TLS options are taken out of the query, anything else is kept for the
+  // DNS over HTTPS endpoint
+  let mut options = TlsOptions::default();
+  let mut query = Vec::new();
+  for (key, value) in url.query_pairs() {
+    if !options.set(&key, &value)? {
+      query.push((key.into_owned(), value.into_owned()));
+    }
+  }
+  if query.is_empty() {
+    url.set_query(None);
+  } else {
+    url.query_pairs_mut().clear().extend_pairs(query);
+  }
+
+  let host = match url.host() {
+    Some(Host::Domain(domain)) => domain.to_string(),
+    Some(Host::Ipv4(ip)) => ip.to_string(),
+    Some(Host::Ipv6(ip)) => ip.to_string(),
+    None => return Err(anyhow!("Missing host in upstream {}", raw)),
+  };
+
+  match url.scheme() {
+    "tls" | "quic" if url.query().is_some() => Err(anyhow!(
+      "Invalid TLS option in upstream {}, allowed: [ca, cert, key, pin, sni]",
+      raw
+    )),
+    "tls" => Ok(UpstreamDns::TlsHost(
+      host,
+      url.port().unwrap_or(853),
+      options,
+    )),
+    "https" => Ok(UpstreamDns::HttpsUrl(url, options)),
+    // the port of DNS over QUIC is the one of DNS over TLS (RFC 9250 4.1.1)
+    "quic" => Ok(UpstreamDns::QuicHost(
+      host,
+      url.port().unwrap_or(853),
+      options,
+    )),
+    "h3" => Ok(UpstreamDns::H3Url(url, options)),
+    unknown => Err(anyhow!(
+      "Invalid upstream url scheme {}, allowed: [tls, https, quic, h3]",
+      unknown
+    )),
+  }
+}
+
+/// Encrypted upstream at a fixed address, e.g. `tls:1.1.1.1:853/cloudflare-dns.com`.
+fn parse_encrypted_upstream(
+  protocol: &str,
+  host: &str,
+) -> anyhow::Result<(SocketAddr, String, TlsOptions)> {
+  let (host, options) = split_tls_options(host)?;
+  let (addr, domain) = host
+    .split_once('/')
+    .ok_or_else(|| anyhow!("Missing domain name for {} upstream", protocol))?;
+
+  Ok((addr.parse()?, domain.to_string(), options))
+}
+
+/// Splits TLS options given as query off an upstream, e.g.
+/// `1.1.1.1:853/cloudflare-dns.com?pin=sha256/...`.
+fn split_tls_options(host: &str) -> anyhow::Result<(&str, TlsOptions)> {
+  let mut options = TlsOptions::default();
+
+  let Some((host, query)) = host.split_once('?') else {
+    return Ok((host, options));
+  };
+
+  for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+    if !options.set(&key, &value)? {
+      return Err(anyhow!(
+        "Invalid TLS option {}, allowed: [ca, cert, key, pin, sni]",
+        key
+      ));
+    }
+  }
+
+  Ok((host, options))
+}
+
+pub(super) fn parse_path(s: &str) -> Result<String, String> {
+  if s.starts_with('/') {
+    Ok(s.to_string())
+  } else {
+    Err(format!("Path {} must start with /", s))
+  }
+}
@@ -0,0 +1,86 @@
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use trust_dns_server::authority::{
+  AuthorityObject, LookupError, LookupObject, LookupOptions, MessageRequest, UpdateResult, ZoneType,
+};
+use trust_dns_server::proto::op::ResponseCode;
+use trust_dns_server::proto::rr::{LowerName, RecordType};
+use trust_dns_server::server::RequestInfo;
+use trust_dns_server::store::forwarder::ForwardLookup;
+
+use crate::upstream::UpstreamGroup;
+
+/// Forwards every query of a zone to its upstreams.
+pub(crate) struct UpstreamAuthority {
+  upstreams: Arc<UpstreamGroup>,
+}
+
+impl UpstreamAuthority {
+  pub(crate) fn new(upstreams: Arc<UpstreamGroup>) -> Self {
+    Self { upstreams }
+  }
+}
+
+#[async_trait]
+impl AuthorityObject for UpstreamAuthority {
+  fn box_clone(&self) -> Box<dyn AuthorityObject> {
+    Box::new(UpstreamAuthority {
+      upstreams: self.upstreams.clone(),
+    })
+  }
+
+  fn zone_type(&self) -> ZoneType {
+    ZoneType::Forward
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    false
+  }
+
+  async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
+    Err(ResponseCode::NotImp)
+  }
+
+  fn origin(&self) -> &LowerName {
+    self.upstreams.zone()
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    rtype: RecordType,
+    _lookup_options: LookupOptions,
+  ) -> Result<Box<dyn LookupObject>, LookupError> {
+    match self.upstreams.lookup(name, rtype).await {
+      Ok(lookup) => Ok(Box::new(ForwardLookup(lookup))),
+      Err(err) => Err(LookupError::from(err)),
+    }
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Box<dyn LookupObject>, LookupError> {
+    self
+      .lookup(
+        request_info.query.name(),
+        request_info.query.query_type(),
+        lookup_options,
+      )
+      .await
+  }
+
+  async fn get_nsec_records(
+    &self,
+    _name: &LowerName,
+    _lookup_options: LookupOptions,
+  ) -> Result<Box<dyn LookupObject>, LookupError> {
+    Err(LookupError::from(io::Error::new(
+      io::ErrorKind::Other,
+      "Getting NSEC records is not supported for forwarded zones",
+    )))
+  }
+}
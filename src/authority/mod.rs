@@ -1 +1,3 @@
+pub(crate) mod forward;
 pub(crate) mod netbox;
+pub(crate) mod traced;
@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -6,6 +6,7 @@ use async_trait::async_trait;
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
 use reqwest::Client;
 use serde::Deserialize;
+use tracing::{debug, instrument};
 use trust_dns_server::authority::{
   AuthorityObject, LookupError, LookupObject, LookupOptions, LookupRecords, MessageRequest,
   UpdateResult, ZoneType,
@@ -22,7 +23,8 @@ struct SearchResponse<T> {
 
 #[derive(Deserialize)]
 struct IpAddress {
-  assigned_object: AssignedObject,
+  /// Missing for unassigned addresses.
+  assigned_object: Option<AssignedObject>,
 }
 
 #[derive(Deserialize)]
@@ -62,12 +64,31 @@ impl NetboxClient {
 
     let ip = Ipv4Addr::new(d, c, b, a);
 
-    println!("{}", ip);
+    debug!("Looking up {} in netbox", ip);
 
+    self.lookup(IpAddr::V4(ip)).await
+  }
+
+  /// Checks NetBox is reachable and accepts the token.
+  pub(crate) async fn ping(&self) -> anyhow::Result<()> {
+    self
+      .client
+      .get(self.base_url.join("api/status/")?)
+      .header(AUTHORIZATION, format!("Token {}", self.token))
+      .send()
+      .await?
+      .error_for_status()?;
+
+    Ok(())
+  }
+
+  /// Names of the devices and virtual machines the address is assigned to.
+  #[instrument(name = "netbox", skip_all, fields(ip = %ip))]
+  pub(crate) async fn lookup(&self, ip: IpAddr) -> anyhow::Result<Vec<LowerName>> {
     let response = self
       .client
-      .get(self.base_url.join("api/ipam/ip-addresses/?q={}")?)
-      .query(&[('q', ip)])
+      .get(self.base_url.join("api/ipam/ip-addresses/")?)
+      .query(&[("address", ip)])
       .header(CONTENT_TYPE, "application/json")
       .header(AUTHORIZATION, format!("Token {}", self.token))
       .send()
@@ -78,20 +99,21 @@ impl NetboxClient {
 
     let mut names = Vec::new();
 
-    for result in search_response.results {
-      let name = result
-        .assigned_object
-        .device
-        .map(|device| device.name)
-        .or_else(|| {
-          result
-            .assigned_object
-            .virtual_machine
-            .map(|virtual_machine| virtual_machine.name)
-        })
-        .expect("can't be");
-
-      names.push(LowerName::from_str(&name)?);
+    // addresses may also be assigned to FHRP groups or nothing at all
+    for assigned in search_response
+      .results
+      .into_iter()
+      .filter_map(|result| result.assigned_object)
+    {
+      let name = assigned.device.map(|device| device.name).or_else(|| {
+        assigned
+          .virtual_machine
+          .map(|virtual_machine| virtual_machine.name)
+      });
+
+      if let Some(name) = name {
+        names.push(LowerName::from_str(&name)?);
+      }
     }
 
     Ok(names)
@@ -0,0 +1,95 @@
+use async_trait::async_trait;
+use tracing::{info_span, Instrument};
+use trust_dns_server::authority::{
+  AuthorityObject, LookupError, LookupObject, LookupOptions, MessageRequest, UpdateResult, ZoneType,
+};
+use trust_dns_server::proto::rr::{LowerName, RecordType};
+use trust_dns_server::server::RequestInfo;
+
+/// Wraps an authority to record a span for every lookup it answers. Query
+/// names are left to the request span, which respects the privacy settings.
+pub(crate) struct TracedAuthority {
+  inner: Box<dyn AuthorityObject>,
+  kind: &'static str,
+  zone: LowerName,
+}
+
+impl TracedAuthority {
+  pub(crate) fn new(kind: &'static str, zone: LowerName, inner: Box<dyn AuthorityObject>) -> Self {
+    Self { inner, kind, zone }
+  }
+}
+
+#[async_trait]
+impl AuthorityObject for TracedAuthority {
+  fn box_clone(&self) -> Box<dyn AuthorityObject> {
+    Box::new(TracedAuthority {
+      inner: self.inner.box_clone(),
+      kind: self.kind,
+      zone: self.zone.clone(),
+    })
+  }
+
+  fn zone_type(&self) -> ZoneType {
+    self.inner.zone_type()
+  }
+
+  fn is_axfr_allowed(&self) -> bool {
+    self.inner.is_axfr_allowed()
+  }
+
+  async fn update(&self, update: &MessageRequest) -> UpdateResult<bool> {
+    self.inner.update(update).await
+  }
+
+  fn origin(&self) -> &LowerName {
+    self.inner.origin()
+  }
+
+  async fn lookup(
+    &self,
+    name: &LowerName,
+    rtype: RecordType,
+    lookup_options: LookupOptions,
+  ) -> Result<Box<dyn LookupObject>, LookupError> {
+    let span = info_span!(
+      "lookup",
+      authority = self.kind,
+      zone = %self.zone,
+      qtype = %rtype
+    );
+
+    self
+      .inner
+      .lookup(name, rtype, lookup_options)
+      .instrument(span)
+      .await
+  }
+
+  async fn search(
+    &self,
+    request_info: RequestInfo<'_>,
+    lookup_options: LookupOptions,
+  ) -> Result<Box<dyn LookupObject>, LookupError> {
+    let span = info_span!(
+      "search",
+      authority = self.kind,
+      zone = %self.zone,
+      qtype = %request_info.query.query_type()
+    );
+
+    self
+      .inner
+      .search(request_info, lookup_options)
+      .instrument(span)
+      .await
+  }
+
+  async fn get_nsec_records(
+    &self,
+    name: &LowerName,
+    lookup_options: LookupOptions,
+  ) -> Result<Box<dyn LookupObject>, LookupError> {
+    self.inner.get_nsec_records(name, lookup_options).await
+  }
+}
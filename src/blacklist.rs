@@ -1,6 +1,7 @@
 use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use fnv::FnvHasher;
@@ -10,18 +11,25 @@ use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::task::JoinSet;
 use tokio_util::io::StreamReader;
 use tracing::{error, info};
-use trust_dns_server::proto::rr::LowerName;
+use trust_dns_server::proto::rr::{LowerName, Name};
 use url::Url;
 
+#[derive(Clone)]
 pub(crate) struct Blacklist {
-  blacklist: Vec<u64>,
+  /// Shared by the copies with other exceptions.
+  blacklist: Arc<Vec<u64>>,
   sources: HashSet<Url>,
+  /// Names never blocked and names always blocked, with their subdomains.
+  allow: Vec<LowerName>,
+  deny: Vec<LowerName>,
+  /// Whether an update completed, the list is empty before.
+  loaded: bool,
 }
 
 impl Blacklist {
   pub(crate) fn new() -> Self {
     Self {
-      blacklist: Vec::default(),
+      blacklist: Arc::default(),
       sources: HashSet::from_iter(vec![
         Url::parse("https://raw.githubusercontent.com/hagezi/dns-blocklists/main/domains/multi.txt").unwrap(),
         Url::parse("https://raw.githubusercontent.com/anudeepND/blacklist/master/adservers.txt").unwrap(),
@@ -109,10 +117,55 @@ impl Blacklist {
         Url::parse("https://raw.githubusercontent.com/RPiList/specials/master/Blocklisten/Win10Telemetry").unwrap(),
         Url::parse("https://raw.githubusercontent.com/wlqY8gkVb9w1Ck5MVD4lBre9nWJez8/W10TelemetryBlocklist/master/W10TelemetryBlocklist").unwrap(),
       ]),
+      allow: Vec::new(),
+      deny: Vec::new(),
+      loaded: false,
     }
   }
 
+  /// Blacklist fed by the given lists instead of the built-in ones.
+  pub(crate) fn with_sources(sources: Vec<Url>) -> Self {
+    Self {
+      blacklist: Arc::default(),
+      sources: HashSet::from_iter(sources),
+      allow: Vec::new(),
+      deny: Vec::new(),
+      loaded: false,
+    }
+  }
+
+  /// Same blacklist with other names allowed and denied.
+  pub(crate) fn with_exceptions(self, allow: &[Name], deny: &[Name]) -> Self {
+    Self {
+      allow: allow.iter().cloned().map(LowerName::from).collect(),
+      deny: deny.iter().cloned().map(LowerName::from).collect(),
+      ..self
+    }
+  }
+
+  /// Same exceptions as the other blacklist.
+  pub(crate) fn with_exceptions_of(self, other: &Blacklist) -> Self {
+    Self {
+      allow: other.allow.clone(),
+      deny: other.deny.clone(),
+      ..self
+    }
+  }
+
+  pub(crate) fn sources(&self) -> impl Iterator<Item = &Url> {
+    self.sources.iter()
+  }
+
+  pub(crate) fn allow(&self) -> &[LowerName] {
+    &self.allow
+  }
+
+  pub(crate) fn deny(&self) -> &[LowerName] {
+    &self.deny
+  }
+
   pub(crate) async fn update(&mut self) -> anyhow::Result<()> {
+    let blacklist = Arc::make_mut(&mut self.blacklist);
     let mut join_set = JoinSet::new();
 
     let client = Client::new();
@@ -130,17 +183,17 @@ impl Blacklist {
           let count = hashes.len();
           let mut actual = count;
           for hash in hashes {
-            match self.blacklist.binary_search(&hash) {
+            match blacklist.binary_search(&hash) {
               Ok(_) => actual -= 1,
-              Err(_) => self.blacklist.push(hash),
+              Err(_) => blacklist.push(hash),
             }
           }
-          self.blacklist.shrink_to_fit();
+          blacklist.shrink_to_fit();
           info!(
             "Added {} new of {} names ({} total), {} sources remaining",
             actual,
             count,
-            self.blacklist.len(),
+            blacklist.len(),
             join_set.len()
           );
         }
@@ -148,7 +201,8 @@ impl Blacklist {
       }
     }
 
-    self.blacklist.sort();
+    blacklist.sort();
+    self.loaded = true;
 
     Ok(())
   }
@@ -214,7 +268,22 @@ impl Blacklist {
     Ok(hashes)
   }
 
+  pub(crate) fn is_loaded(&self) -> bool {
+    self.loaded
+  }
+
+  pub(crate) fn len(&self) -> usize {
+    self.blacklist.len()
+  }
+
   pub(crate) fn is_blocked(&self, qname: &LowerName) -> bool {
+    if self.allow.iter().any(|name| name.zone_of(qname)) {
+      return false;
+    }
+    if self.deny.iter().any(|name| name.zone_of(qname)) {
+      return true;
+    }
+
     let mut hasher = FnvHasher::default();
 
     let string = qname.to_string();
@@ -0,0 +1,774 @@
+use std::collections::HashMap;
+use std::iter;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, error, Span};
+use trust_dns_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+use trust_dns_server::proto::op::{Edns, Header, Message, MessageType, OpCode, ResponseCode};
+use trust_dns_server::proto::rr::{DNSClass, LowerName, RData, Record, RecordType};
+use trust_dns_server::proto::serialize::binary::{BinDecodable, BinEncodable};
+use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+use crate::capture::Capture;
+
+/// How long stale entries are answered without trying to refresh them
+/// after a refresh failed (RFC 8767 section 4).
+const FAILURE_RECHECK: Duration = Duration::from_secs(30);
+
+pub(crate) struct CacheConfig {
+  /// Approximate upper bound of the memory used by cached responses, 0
+  /// disables the cache.
+  pub(crate) max_memory: usize,
+  pub(crate) min_ttl: Duration,
+  pub(crate) max_ttl: Duration,
+  /// Upper bound for negative responses (RFC 2308).
+  pub(crate) negative_max_ttl: Duration,
+  /// Zones whose responses are never cached.
+  pub(crate) bypass: Vec<LowerName>,
+  /// How long expired responses are kept to answer with while upstreams
+  /// fail (RFC 8767), 0 disables serving stale responses.
+  pub(crate) serve_stale: Duration,
+  /// TTL of stale records in responses.
+  pub(crate) stale_ttl: Duration,
+  /// How long to wait for a refresh before answering with a stale response.
+  pub(crate) stale_timeout: Duration,
+  /// Hits after which an entry is refreshed before it expires, 0 disables
+  /// prefetching.
+  pub(crate) prefetch_min_hits: u64,
+  /// Remaining percentage of the TTL at which popular entries are refreshed.
+  pub(crate) prefetch_threshold: u8,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+  name: LowerName,
+  query_type: RecordType,
+  query_class: DNSClass,
+  dnssec_ok: bool,
+}
+
+struct Response {
+  header: Header,
+  answers: Vec<Record>,
+  name_servers: Vec<Record>,
+  additionals: Vec<Record>,
+}
+
+struct Cached {
+  response: Response,
+  inserted: Instant,
+  ttl: Duration,
+  expires: Instant,
+  size: usize,
+  hits: u64,
+  refreshing: bool,
+  /// When the last refresh failed.
+  failed: Option<Instant>,
+}
+
+enum Lookup {
+  Fresh {
+    response: Response,
+    prefetch: bool,
+  },
+  /// Expired response, `refresh` is set if no refresh is running yet and
+  /// none failed recently.
+  Stale {
+    response: Response,
+    refresh: bool,
+  },
+  Miss,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CacheStats {
+  hits: u64,
+  stale_hits: u64,
+  misses: u64,
+  prefetches: u64,
+  evictions: u64,
+  entries: usize,
+  memory: usize,
+}
+
+/// Responses of all authorities, keyed by question.
+pub(crate) struct ResponseCache {
+  config: CacheConfig,
+  state: Mutex<State>,
+  hits: AtomicU64,
+  stale_hits: AtomicU64,
+  misses: AtomicU64,
+  prefetches: AtomicU64,
+  evictions: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+  entries: HashMap<Key, Cached>,
+  memory: usize,
+}
+
+impl ResponseCache {
+  pub(crate) fn new(config: CacheConfig) -> Self {
+    Self {
+      config,
+      state: Mutex::new(State::default()),
+      hits: AtomicU64::new(0),
+      stale_hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+      prefetches: AtomicU64::new(0),
+      evictions: AtomicU64::new(0),
+    }
+  }
+
+  fn enabled(&self) -> bool {
+    self.config.max_memory > 0
+  }
+
+  fn bypassed(&self, name: &LowerName) -> bool {
+    self.config.bypass.iter().any(|zone| zone.zone_of(name))
+  }
+
+  /// Looks up a response and marks the entry as refreshing if the caller
+  /// is expected to refresh it.
+  fn get(&self, key: &Key) -> Lookup {
+    let now = Instant::now();
+    let mut state = self.state.lock().unwrap();
+
+    let Some(cached) = state.entries.get_mut(key) else {
+      self.misses.fetch_add(1, Ordering::Relaxed);
+      return Lookup::Miss;
+    };
+
+    if cached.expires > now {
+      self.hits.fetch_add(1, Ordering::Relaxed);
+      cached.hits += 1;
+
+      let threshold = cached.ttl * u32::from(self.config.prefetch_threshold) / 100;
+      let prefetch = self.config.prefetch_min_hits > 0
+        && cached.hits >= self.config.prefetch_min_hits
+        && cached.expires - now < threshold
+        && cached.may_refresh(now);
+      if prefetch {
+        self.prefetches.fetch_add(1, Ordering::Relaxed);
+        cached.refreshing = true;
+      }
+
+      return Lookup::Fresh {
+        response: cached.aged(now),
+        prefetch,
+      };
+    }
+
+    if cached.expires + self.config.serve_stale > now {
+      self.stale_hits.fetch_add(1, Ordering::Relaxed);
+      let refresh = cached.may_refresh(now);
+      if refresh {
+        cached.refreshing = true;
+      }
+
+      return Lookup::Stale {
+        response: cached.stale(self.config.stale_ttl),
+        refresh,
+      };
+    }
+
+    self.misses.fetch_add(1, Ordering::Relaxed);
+    Lookup::Miss
+  }
+
+  /// Records a failed refresh, the entry is refreshed again once
+  /// `FAILURE_RECHECK` passed.
+  fn refresh_failed(&self, key: &Key) {
+    if let Some(cached) = self.state.lock().unwrap().entries.get_mut(key) {
+      cached.refreshing = false;
+      cached.failed = Some(Instant::now());
+    }
+  }
+
+  fn insert(&self, key: Key, message: &Message, wire_size: usize) {
+    let Some(ttl) = self.ttl(message) else {
+      // keep serving a stale entry rather than replacing it with a failure
+      self.refresh_failed(&key);
+      return;
+    };
+
+    // the SOA of negative answers must not outlive the entry (RFC 2308 section 5)
+    let max_ttl = if is_negative(message) {
+      ttl.as_secs() as u32
+    } else {
+      self.config.max_ttl.as_secs() as u32
+    };
+    let clamp = |records: &[Record]| {
+      records
+        .iter()
+        .map(|record| {
+          let mut record = record.clone();
+          record.set_ttl(record.ttl().min(max_ttl));
+          record
+        })
+        .collect::<Vec<_>>()
+    };
+
+    let record_count =
+      message.answers().len() + message.name_servers().len() + message.additionals().len();
+
+    let now = Instant::now();
+    let mut cached = Cached {
+      response: Response {
+        header: *message.header(),
+        answers: clamp(message.answers()),
+        name_servers: clamp(message.name_servers()),
+        additionals: clamp(message.additionals()),
+      },
+      inserted: now,
+      ttl,
+      expires: now + ttl,
+      size: size_of::<Key>() + size_of::<Cached>() + record_count * size_of::<Record>() + wire_size,
+      hits: 0,
+      refreshing: false,
+      failed: None,
+    };
+
+    let mut state = self.state.lock().unwrap();
+    // popular entries stay popular when refreshed, so they keep being prefetched
+    if let Some(replaced) = state.entries.get(&key) {
+      cached.hits = replaced.hits;
+    }
+    state.memory += cached.size;
+    if let Some(replaced) = state.entries.insert(key, cached) {
+      state.memory -= replaced.size;
+    }
+
+    if state.memory > self.config.max_memory {
+      self.evict(&mut state, now);
+    }
+  }
+
+  /// Drops entries past serving stale and, if that is not enough, the
+  /// entries closest to expiry until a quarter of the memory is free again,
+  /// so the cost is amortized over many inserts.
+  fn evict(&self, state: &mut State, now: Instant) {
+    let before = state.entries.len();
+    state
+      .entries
+      .retain(|_, cached| cached.expires + self.config.serve_stale > now);
+
+    let target = self.config.max_memory / 4 * 3;
+    let mut memory = state
+      .entries
+      .values()
+      .map(|cached| cached.size)
+      .sum::<usize>();
+
+    if memory > target {
+      let mut expiries = state
+        .entries
+        .iter()
+        .map(|(key, cached)| (cached.expires, cached.size, key.clone()))
+        .collect::<Vec<_>>();
+      expiries.sort_unstable_by_key(|(expires, _, _)| *expires);
+
+      for (_, size, key) in expiries {
+        if memory <= target {
+          break;
+        }
+        state.entries.remove(&key);
+        memory -= size;
+      }
+    }
+
+    state.memory = memory;
+    self
+      .evictions
+      .fetch_add((before - state.entries.len()) as u64, Ordering::Relaxed);
+  }
+
+  /// How long a response may be cached: the lowest record TTL for positive
+  /// answers, the SOA minimum for negative ones. Anything else, e.g. server
+  /// failures, isn't cached at all.
+  fn ttl(&self, message: &Message) -> Option<Duration> {
+    if message.truncated() {
+      return None;
+    }
+
+    let (ttl, max_ttl) = match message.response_code() {
+      ResponseCode::NoError if !is_negative(message) => (
+        message
+          .answers()
+          .iter()
+          .chain(message.name_servers())
+          .map(Record::ttl)
+          .min()?,
+        self.config.max_ttl,
+      ),
+      ResponseCode::NoError | ResponseCode::NXDomain => (
+        message
+          .name_servers()
+          .iter()
+          .find_map(|record| match record.data() {
+            Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+            _ => None,
+          })?,
+        self.config.negative_max_ttl.min(self.config.max_ttl),
+      ),
+      _ => return None,
+    };
+
+    let ttl = Duration::from_secs(ttl.into())
+      .max(self.config.min_ttl)
+      .min(max_ttl);
+
+    (!ttl.is_zero()).then_some(ttl)
+  }
+
+  /// Drops the entries of the name, with `subdomains` also the entries
+  /// below it, or all entries without a name. Returns how many were dropped.
+  pub(crate) fn flush(&self, name: Option<&LowerName>, subdomains: bool) -> usize {
+    let mut state = self.state.lock().unwrap();
+    let before = state.entries.len();
+
+    state.entries.retain(|key, _| match name {
+      Some(name) if subdomains => !name.zone_of(&key.name),
+      Some(name) => key.name != *name,
+      None => false,
+    });
+    state.memory = state.entries.values().map(|cached| cached.size).sum();
+
+    before - state.entries.len()
+  }
+
+  pub(crate) fn stats(&self) -> CacheStats {
+    let state = self.state.lock().unwrap();
+
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      stale_hits: self.stale_hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      prefetches: self.prefetches.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+      entries: state.entries.len(),
+      memory: state.memory,
+    }
+  }
+}
+
+impl Cached {
+  /// Whether neither a refresh is running nor one failed recently.
+  fn may_refresh(&self, now: Instant) -> bool {
+    !self.refreshing
+      && self.failed.map_or(true, |failed| {
+        now.saturating_duration_since(failed) >= FAILURE_RECHECK
+      })
+  }
+
+  /// Response with its TTLs reduced by the time spent in the cache.
+  fn aged(&self, now: Instant) -> Response {
+    let elapsed = (now - self.inserted).as_secs() as u32;
+    // a raised minimum TTL must not count down to zero before the entry expires
+    let remaining = (self.expires - now).as_secs().max(1) as u32;
+
+    self.with_ttl(|ttl| ttl.saturating_sub(elapsed).max(remaining))
+  }
+
+  fn stale(&self, ttl: Duration) -> Response {
+    self.with_ttl(|_| ttl.as_secs() as u32)
+  }
+
+  fn with_ttl(&self, ttl: impl Fn(u32) -> u32) -> Response {
+    let records = |records: &[Record]| {
+      records
+        .iter()
+        .map(|record| {
+          let mut record = record.clone();
+          record.set_ttl(ttl(record.ttl()));
+          record
+        })
+        .collect::<Vec<_>>()
+    };
+
+    Response {
+      header: self.response.header,
+      answers: records(&self.response.answers),
+      name_servers: records(&self.response.name_servers),
+      additionals: records(&self.response.additionals),
+    }
+  }
+}
+
+/// Answers queries from the [`ResponseCache`] and caches the responses of
+/// the delegate. Expired entries are refreshed in the background, either
+/// shortly before they expire if they are popular or when served stale.
+pub(crate) struct CachingHandler<T>(Arc<Inner<T>>);
+
+struct Inner<T> {
+  cache: Arc<ResponseCache>,
+  delegate: T,
+}
+
+impl<T> Clone for CachingHandler<T> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+impl<T: RequestHandler> CachingHandler<T> {
+  pub(crate) fn new(cache: Arc<ResponseCache>, delegate: T) -> Self {
+    Self(Arc::new(Inner { cache, delegate }))
+  }
+
+  fn key(&self, request: &Request) -> Option<Key> {
+    if !self.0.cache.enabled()
+      || request.message_type() != MessageType::Query
+      || request.op_code() != OpCode::Query
+      || request.edns().map_or(false, |edns| edns.version() > 0)
+    {
+      return None;
+    }
+
+    let query = request.query();
+    if self.0.cache.bypassed(query.name()) {
+      return None;
+    }
+
+    Some(Key {
+      name: query.name().clone(),
+      query_type: query.query_type(),
+      query_class: query.query_class(),
+      dnssec_ok: request.edns().map_or(false, Edns::dnssec_ok),
+    })
+  }
+
+  /// Asks the delegate and returns its response along with its encoded size.
+  async fn resolve(&self, request: &Request) -> Option<(Message, usize)> {
+    let capture = Capture::default();
+    self
+      .0
+      .delegate
+      .handle_request(request, capture.clone())
+      .await;
+
+    let wire = capture.take();
+    let Some(wire) = wire else {
+      error!("Delegate did not send a response");
+      return None;
+    };
+
+    match Message::from_vec(&wire) {
+      Ok(message) => Some((message, wire.len())),
+      Err(err) => {
+        error!("Unable to decode response: {}", err);
+        None
+      }
+    }
+  }
+
+  /// Resolves the request again in the background and caches the response.
+  fn refresh(&self, request: &Request, key: Key) -> JoinHandle<Option<Message>> {
+    let handler = self.clone();
+    // the request only lives as long as the client is waiting
+    let request = request
+      .to_bytes()
+      .and_then(|bytes| MessageRequest::from_bytes(&bytes))
+      .map(|message| Request::new(message, request.src(), request.protocol()));
+
+    tokio::spawn(async move {
+      let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+          debug!("Unable to copy request for refresh: {}", err);
+          handler.0.cache.refresh_failed(&key);
+          return None;
+        }
+      };
+
+      match handler.resolve(&request).await {
+        Some((message, size)) => {
+          handler.0.cache.insert(key, &message, size);
+          Some(message)
+        }
+        None => {
+          handler.0.cache.refresh_failed(&key);
+          None
+        }
+      }
+    })
+  }
+
+  /// Waits up to `stale_timeout` for a refresh and falls back to the stale
+  /// response if the refresh fails or takes too long (RFC 8767).
+  async fn respond_stale<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+    key: Key,
+    stale: Response,
+  ) -> ResponseInfo {
+    let refresh = self.refresh(request, key);
+
+    match timeout(self.0.cache.config.stale_timeout, refresh).await {
+      Ok(Ok(Some(message)))
+        if matches!(
+          message.response_code(),
+          ResponseCode::NoError | ResponseCode::NXDomain
+        ) =>
+      {
+        self
+          .respond_message(request, response_handle, &message)
+          .await
+      }
+      _ => self.respond_cached(request, response_handle, stale).await,
+    }
+  }
+
+  async fn respond_cached<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    mut response_handle: R,
+    cached: Response,
+  ) -> ResponseInfo {
+    let mut header = Header::response_from_request(request.header());
+    header
+      .set_response_code(cached.header.response_code())
+      .set_authoritative(cached.header.authoritative())
+      .set_recursion_available(cached.header.recursion_available())
+      .set_authentic_data(cached.header.authentic_data());
+
+    let mut builder = MessageResponseBuilder::from_message_request(request);
+    if let Some(request_edns) = request.edns() {
+      // the same as the catalog answers with
+      let mut edns = Edns::new();
+      edns.set_dnssec_ok(true);
+      edns.set_max_payload(request_edns.max_payload().max(512));
+      edns.set_version(0);
+      builder.edns(edns);
+    }
+
+    let response = builder.build(
+      header,
+      cached.answers.iter(),
+      cached.name_servers.iter(),
+      iter::empty(),
+      cached.additionals.iter(),
+    );
+
+    send(&mut response_handle, response, request.header()).await
+  }
+
+  async fn respond_uncached<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    mut response_handle: R,
+    key: Key,
+  ) -> ResponseInfo {
+    let Some((message, size)) = self.resolve(request).await else {
+      let response = MessageResponseBuilder::from_message_request(request)
+        .error_msg(request.header(), ResponseCode::ServFail);
+      return send(&mut response_handle, response, request.header()).await;
+    };
+
+    self.0.cache.insert(key, &message, size);
+    self
+      .respond_message(request, response_handle, &message)
+      .await
+  }
+
+  async fn respond_message<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    mut response_handle: R,
+    message: &Message,
+  ) -> ResponseInfo {
+    let mut builder = MessageResponseBuilder::from_message_request(request);
+    if let Some(edns) = message.edns() {
+      builder.edns(edns.clone());
+    }
+
+    let response = builder.build(
+      *message.header(),
+      message.answers().iter(),
+      message.name_servers().iter(),
+      iter::empty(),
+      message.additionals().iter(),
+    );
+
+    send(&mut response_handle, response, request.header()).await
+  }
+}
+
+#[async_trait]
+impl<T: RequestHandler> RequestHandler for CachingHandler<T> {
+  async fn handle_request<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+  ) -> ResponseInfo {
+    let Some(key) = self.key(request) else {
+      return self
+        .0
+        .delegate
+        .handle_request(request, response_handle)
+        .await;
+    };
+
+    match self.0.cache.get(&key) {
+      Lookup::Fresh { response, prefetch } => {
+        Span::current().record("cache", "hit");
+        if prefetch {
+          self.refresh(request, key);
+        }
+        self
+          .respond_cached(request, response_handle, response)
+          .await
+      }
+      Lookup::Stale { response, refresh } => {
+        Span::current().record("cache", "stale");
+        if refresh {
+          self
+            .respond_stale(request, response_handle, key, response)
+            .await
+        } else {
+          self
+            .respond_cached(request, response_handle, response)
+            .await
+        }
+      }
+      Lookup::Miss => {
+        Span::current().record("cache", "miss");
+        self.respond_uncached(request, response_handle, key).await
+      }
+    }
+  }
+}
+
+/// Whether the message is an NXDOMAIN or NODATA response.
+fn is_negative(message: &Message) -> bool {
+  message.response_code() == ResponseCode::NXDomain || message.answers().is_empty()
+}
+
+async fn send<'a, R: ResponseHandler>(
+  response_handle: &mut R,
+  response: MessageResponse<
+    '_,
+    'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+    impl Iterator<Item = &'a Record> + Send + 'a,
+  >,
+  request_header: &Header,
+) -> ResponseInfo {
+  match response_handle.send_response(response).await {
+    Ok(info) => info,
+    Err(err) => {
+      debug!("Unable to send response: {}", err);
+      let mut header = Header::response_from_request(request_header);
+      header.set_response_code(ResponseCode::ServFail);
+      header.into()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use trust_dns_server::proto::rr::rdata::{A, SOA};
+  use trust_dns_server::proto::rr::Name;
+
+  use super::*;
+
+  fn cache() -> ResponseCache {
+    ResponseCache::new(CacheConfig {
+      max_memory: 1 << 20,
+      min_ttl: Duration::ZERO,
+      max_ttl: Duration::from_secs(86400),
+      negative_max_ttl: Duration::from_secs(300),
+      bypass: Vec::new(),
+      serve_stale: Duration::ZERO,
+      stale_ttl: Duration::from_secs(30),
+      stale_timeout: Duration::from_millis(400),
+      prefetch_min_hits: 0,
+      prefetch_threshold: 10,
+    })
+  }
+
+  fn key(query_type: RecordType) -> Key {
+    Key {
+      name: LowerName::from(Name::from_str("host.example.com.").unwrap()),
+      query_type,
+      query_class: DNSClass::IN,
+      dnssec_ok: false,
+    }
+  }
+
+  fn soa(ttl: u32) -> Record {
+    let zone = Name::from_str("example.com.").unwrap();
+    let soa = SOA::new(
+      Name::from_str("ns.example.com.").unwrap(),
+      Name::from_str("hostmaster.example.com.").unwrap(),
+      1,
+      3600,
+      600,
+      86400,
+      ttl,
+    );
+    Record::from_rdata(zone, ttl, RData::SOA(soa))
+  }
+
+  fn fresh(cache: &ResponseCache, key: &Key) -> Response {
+    match cache.get(key) {
+      Lookup::Fresh { response, .. } => response,
+      _ => panic!("not cached"),
+    }
+  }
+
+  #[test]
+  fn clamps_soa_of_negative_answers() {
+    let cache = cache();
+
+    for response_code in [ResponseCode::NXDomain, ResponseCode::NoError] {
+      let mut message = Message::new();
+      message
+        .set_message_type(MessageType::Response)
+        .set_response_code(response_code)
+        .add_name_server(soa(3600));
+
+      let key = key(RecordType::AAAA);
+      cache.insert(key.clone(), &message, 100);
+
+      let response = fresh(&cache, &key);
+      assert_eq!(response.name_servers.len(), 1);
+      assert!(response.name_servers[0].ttl() <= 300);
+    }
+  }
+
+  #[test]
+  fn keeps_ttl_of_positive_answers() {
+    let cache = cache();
+
+    let mut message = Message::new();
+    message
+      .set_message_type(MessageType::Response)
+      .add_answer(Record::from_rdata(
+        Name::from_str("host.example.com.").unwrap(),
+        3600,
+        RData::A(A::new(192, 0, 2, 1)),
+      ))
+      .add_name_server(soa(3600));
+
+    let key = key(RecordType::A);
+    cache.insert(key.clone(), &message, 100);
+
+    let response = fresh(&cache, &key);
+    assert!(response.answers[0].ttl() > 300);
+    assert!(response.name_servers[0].ttl() > 300);
+  }
+}
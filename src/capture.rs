@@ -0,0 +1,63 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use trust_dns_server::authority::MessageResponse;
+use trust_dns_server::proto::rr::Record;
+use trust_dns_server::proto::serialize::binary::BinEncoder;
+use trust_dns_server::server::{ResponseHandler, ResponseInfo};
+
+/// Response handler that keeps the encoded response instead of sending it.
+#[derive(Clone)]
+pub(crate) struct Capture {
+  response: Arc<Mutex<Option<Vec<u8>>>>,
+  max_size: u16,
+}
+
+impl Default for Capture {
+  fn default() -> Self {
+    Self::with_max_size(u16::MAX)
+  }
+}
+
+impl Capture {
+  /// Truncates responses exceeding `max_size`, as needed for UDP.
+  pub(crate) fn with_max_size(max_size: u16) -> Self {
+    Self {
+      response: Arc::default(),
+      max_size,
+    }
+  }
+
+  /// The encoded response, if one was sent.
+  pub(crate) fn take(&self) -> Option<Vec<u8>> {
+    self.response.lock().unwrap().take()
+  }
+}
+
+#[async_trait]
+impl ResponseHandler for Capture {
+  async fn send_response<'a>(
+    &mut self,
+    response: MessageResponse<
+      '_,
+      'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+      impl Iterator<Item = &'a Record> + Send + 'a,
+    >,
+  ) -> io::Result<ResponseInfo> {
+    let mut buf = Vec::with_capacity(512);
+    let info = {
+      let mut encoder = BinEncoder::new(&mut buf);
+      encoder.set_max_size(self.max_size);
+      response
+        .destructive_emit(&mut encoder)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+    };
+
+    *self.response.lock().unwrap() = Some(buf);
+    Ok(info)
+  }
+}
@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use tracing::{info, warn};
+
+/// Certificate and key of the TLS listeners, reloaded when the files
+/// change so renewed certificates are used without a restart.
+pub(crate) struct Certificates {
+  cert_path: PathBuf,
+  key_path: PathBuf,
+  current: RwLock<Arc<CertifiedKey>>,
+  /// Modification times of the certificate and key files last loaded.
+  modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl Certificates {
+  pub(crate) fn load(cert_path: PathBuf, key_path: PathBuf) -> anyhow::Result<Self> {
+    let modified = (modified(&cert_path), modified(&key_path));
+    let current = certified_key(&cert_path, &key_path)?;
+
+    Ok(Self {
+      cert_path,
+      key_path,
+      current: RwLock::new(Arc::new(current)),
+      modified: Mutex::new(modified),
+    })
+  }
+
+  /// Server config using the current certificate for every connection.
+  pub(crate) fn server_config(self: &Arc<Self>, alpn: &[&[u8]]) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder()
+      .with_safe_defaults()
+      .with_no_client_auth()
+      .with_cert_resolver(self.clone());
+
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Arc::new(config)
+  }
+
+  /// Checks the files for changes every `interval` and reloads them. A
+  /// broken certificate or key is logged and the previous one kept.
+  pub(crate) async fn watch(&self, interval: Duration) {
+    loop {
+      tokio::time::sleep(interval).await;
+
+      let modified = (modified(&self.cert_path), modified(&self.key_path));
+      if *self.modified.lock().unwrap() == modified {
+        continue;
+      }
+
+      match certified_key(&self.cert_path, &self.key_path) {
+        Ok(current) => {
+          *self.current.write().unwrap() = Arc::new(current);
+          *self.modified.lock().unwrap() = modified;
+          info!("Reloaded certificate {}", self.cert_path.display());
+        }
+        // certificate and key are usually replaced one after another,
+        // the next check will pick up the complete pair
+        Err(err) => warn!(
+          "Unable to reload certificate {}: {}",
+          self.cert_path.display(),
+          err
+        ),
+      }
+    }
+  }
+}
+
+impl ResolvesServerCert for Certificates {
+  fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    Some(self.current.read().unwrap().clone())
+  }
+}
+
+fn certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+  let certs = load_certs(cert_path)?;
+  let key = sign::any_supported_type(&load_key(key_path)?)
+    .map_err(|_| anyhow!("Unsupported private key in {}", key_path.display()))?;
+
+  Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  path
+    .metadata()
+    .and_then(|metadata| metadata.modified())
+    .ok()
+}
+
+pub(crate) fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+  let mut reader = BufReader::new(File::open(path)?);
+  let certs = rustls_pemfile::certs(&mut reader)?;
+
+  if certs.is_empty() {
+    return Err(anyhow!("No certificates in {}", path.display()));
+  }
+
+  Ok(certs.into_iter().map(Certificate).collect())
+}
+
+pub(crate) fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+  let mut reader = BufReader::new(File::open(path)?);
+
+  for item in rustls_pemfile::read_all(&mut reader)? {
+    match item {
+      Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+      _ => {}
+    }
+  }
+
+  Err(anyhow!("No private key in {}", path.display()))
+}
@@ -0,0 +1,58 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use ipnet::IpNet;
+
+use crate::args::ClientGroupArg;
+
+/// Named groups of client networks, a client belongs to the first group
+/// containing it.
+#[derive(Clone, Default)]
+pub(crate) struct ClientGroups(Arc<Vec<ClientGroup>>);
+
+pub(crate) struct ClientGroup {
+  name: Arc<str>,
+  networks: Vec<IpNet>,
+  /// Whether the blocklist applies to the clients of the group.
+  blocking: bool,
+}
+
+impl ClientGroups {
+  pub(crate) fn new(groups: &[ClientGroupArg], bypass: &[String]) -> Self {
+    Self(Arc::new(
+      groups
+        .iter()
+        .map(|group| ClientGroup {
+          name: Arc::from(group.name.as_str()),
+          networks: group.networks.clone(),
+          blocking: !bypass.contains(&group.name),
+        })
+        .collect(),
+    ))
+  }
+
+  pub(crate) fn group_of(&self, ip: IpAddr) -> Option<&ClientGroup> {
+    self
+      .0
+      .iter()
+      .find(|group| group.networks.iter().any(|network| network.contains(&ip)))
+  }
+
+  pub(crate) fn iter(&self) -> impl Iterator<Item = &ClientGroup> {
+    self.0.iter()
+  }
+}
+
+impl ClientGroup {
+  pub(crate) fn name(&self) -> &Arc<str> {
+    &self.name
+  }
+
+  pub(crate) fn networks(&self) -> &[IpNet] {
+    &self.networks
+  }
+
+  pub(crate) fn is_blocking(&self) -> bool {
+    self.blocking
+  }
+}
@@ -0,0 +1,806 @@
+use std::fmt::{self, Display, Formatter};
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use clap::{ArgMatches, ValueEnum};
+use ipnet::IpNet;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use toml_edit::{Document, Item};
+use tracing_subscriber::EnvFilter;
+use trust_dns_server::resolver::Name;
+use url::Url;
+
+use crate::args::{self, Args, ClientGroupArg, Forwarding, UpstreamDns};
+use crate::certs::Certificates;
+use crate::logging::{LogFormat, LogTarget};
+use crate::privacy::ClientPrivacy;
+use crate::stats::buffer::OverflowPolicy;
+use crate::stats::influx::{Influx, InfluxVersion, WritePrecision};
+use crate::upstream::tls::TlsOptions;
+use crate::upstream::{Strategy, UpstreamGroup};
+
+/// Settings read from a TOML file, e.g.
+///
+/// ```toml
+/// [listen]
+/// udp = ["0.0.0.0:53"]
+///
+/// [[forwarding]]
+/// zone = "."
+/// upstreams = [
+///   "https://security.cloudflare-dns.com/dns-query",
+///   { protocol = "tls", address = "9.9.9.9:853", name = "dns.quad9.net" },
+/// ]
+///
+/// [[client_groups]]
+/// name = "kids"
+/// clients = ["10.0.1.0/24"]
+/// ```
+///
+/// Every setting corresponds to an argument, which takes precedence if it
+/// is given on the command line or as environment variable.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+  listen: ListenSection,
+  tls: TlsSection,
+  doh: DohSection,
+  forwarding: Option<Vec<ForwardingSection>>,
+  client_groups: Option<Vec<ClientGroupSection>>,
+  bootstrap: BootstrapSection,
+  upstream: UpstreamSection,
+  cache: CacheSection,
+  blocklist: BlocklistSection,
+  netbox: Option<NetboxSection>,
+  api: ApiSection,
+  log: LogSection,
+  otlp: OtlpSection,
+  stats: StatsSection,
+  query_log: QueryLogSection,
+  client_names: ClientNamesSection,
+  privacy: PrivacySection,
+  readiness: ReadinessSection,
+  shutdown: ShutdownSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ListenSection {
+  udp: Option<Vec<SocketAddr>>,
+  tcp: Option<Vec<SocketAddr>>,
+  tls: Option<Vec<SocketAddr>>,
+  quic: Option<Vec<SocketAddr>>,
+  https: Option<Vec<SocketAddr>>,
+  http: Option<Vec<SocketAddr>>,
+  api: Option<SocketAddr>,
+  #[serde(deserialize_with = "parsed_all")]
+  trusted_proxies: Option<Vec<IpNet>>,
+  proxy_protocol: ProxyProtocolSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProxyProtocolSection {
+  udp: Option<bool>,
+  tcp: Option<bool>,
+  tls: Option<bool>,
+  http: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+  cert: Option<PathBuf>,
+  key: Option<PathBuf>,
+  reload_interval: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DohSection {
+  #[serde(deserialize_with = "doh_path")]
+  path: Option<String>,
+  json: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardingSection {
+  zone: Parsed<Name>,
+  upstreams: Vec<UpstreamDns>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientGroupSection {
+  name: String,
+  clients: Vec<IpNet>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BootstrapSection {
+  resolvers: Option<Vec<SocketAddr>>,
+  refresh: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UpstreamSection {
+  #[serde(deserialize_with = "value_enum")]
+  strategy: Option<Strategy>,
+  timeout: Option<u64>,
+  health_interval: Option<u64>,
+  failure_threshold: Option<u32>,
+  circuit_open: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheSection {
+  max_memory: Option<usize>,
+  min_ttl: Option<u64>,
+  max_ttl: Option<u64>,
+  negative_max_ttl: Option<u64>,
+  #[serde(deserialize_with = "parsed_all")]
+  bypass: Option<Vec<Name>>,
+  serve_stale: Option<u64>,
+  stale_ttl: Option<u64>,
+  stale_timeout: Option<u64>,
+  prefetch_min_hits: Option<u64>,
+  #[serde(deserialize_with = "percentage")]
+  prefetch_threshold: Option<u8>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BlocklistSection {
+  #[serde(deserialize_with = "parsed_all")]
+  sources: Option<Vec<Url>>,
+  #[serde(deserialize_with = "parsed_all")]
+  allow: Option<Vec<Name>>,
+  #[serde(deserialize_with = "parsed_all")]
+  deny: Option<Vec<Name>>,
+  /// Client groups whose queries are never blocked.
+  bypass: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ApiSection {
+  token: Option<String>,
+}
+
+/// URL and token go together, as their arguments.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetboxSection {
+  url: Parsed<Url>,
+  token: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+  filter: Option<String>,
+  #[serde(deserialize_with = "value_enum")]
+  format: Option<LogFormat>,
+  #[serde(deserialize_with = "value_enum")]
+  target: Option<LogTarget>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct OtlpSection {
+  endpoint: Option<String>,
+  sample_ratio: Option<f64>,
+  filter: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StatsSection {
+  #[serde(deserialize_with = "parsed")]
+  url: Option<Url>,
+  #[serde(deserialize_with = "value_enum")]
+  version: Option<InfluxVersion>,
+  #[serde(deserialize_with = "value_enum")]
+  precision: Option<WritePrecision>,
+  token: Option<String>,
+  bucket: Option<String>,
+  org: Option<String>,
+  database: Option<String>,
+  username: Option<String>,
+  password: Option<String>,
+  buffer_capacity: Option<usize>,
+  batch_size: Option<usize>,
+  #[serde(deserialize_with = "value_enum")]
+  overflow_policy: Option<OverflowPolicy>,
+  sample_rate: Option<u64>,
+  flush_interval: Option<u64>,
+  max_retries: Option<u32>,
+  initial_backoff: Option<u64>,
+  max_backoff: Option<u64>,
+  spool_dir: Option<PathBuf>,
+  spool_max_size: Option<u64>,
+  top_capacity: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QueryLogSection {
+  capacity: Option<usize>,
+  max_memory: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClientNamesSection {
+  netbox: Option<bool>,
+  leases: Option<PathBuf>,
+  resolver: Option<SocketAddr>,
+  ttl: Option<u64>,
+  negative_ttl: Option<u64>,
+  capacity: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PrivacySection {
+  #[serde(deserialize_with = "value_enum")]
+  client: Option<ClientPrivacy>,
+  #[serde(deserialize_with = "ipv4_prefix")]
+  ipv4_prefix: Option<u8>,
+  #[serde(deserialize_with = "ipv6_prefix")]
+  ipv6_prefix: Option<u8>,
+  salt_rotation: Option<u64>,
+  only_blocked: Option<bool>,
+  omit_query: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReadinessSection {
+  #[serde(deserialize_with = "parsed")]
+  probe: Option<Name>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ShutdownSection {
+  timeout: Option<u64>,
+}
+
+impl Config {
+  /// Reads the file with the edits on top, errors point at the offending
+  /// line.
+  pub(crate) fn load(path: &Path, edits: &Edits) -> anyhow::Result<Self> {
+    let mut document = read(path)?;
+    edits.apply(&mut document);
+
+    toml::from_str(&document.to_string())
+      .map_err(|err| anyhow!("Invalid config file {}: {}", path.display(), err))
+  }
+
+  /// Replaces the arguments not given explicitly by the settings of the file.
+  pub(crate) fn apply(self, args: &mut Args, matches: &ArgMatches) {
+    // the id of an argument is the name of its field
+    macro_rules! apply {
+      ($value:expr => $arg:ident) => {
+        if let Some(value) = $value {
+          if !args::is_explicit(matches, stringify!($arg)) {
+            args.$arg = value;
+          }
+        }
+      };
+    }
+
+    let listen = self.listen;
+    apply!(listen.udp => udp_listen_addr);
+    apply!(listen.tcp => tcp_listen_addr);
+    apply!(listen.tls => tls_listen_addr);
+    apply!(listen.quic => quic_listen_addr);
+    apply!(listen.https => https_listen_addr);
+    apply!(listen.http => http_listen_addr);
+    apply!(listen.api.map(Some) => api_listen_addr);
+    apply!(listen.trusted_proxies => trusted_proxies);
+    apply!(listen.proxy_protocol.udp => udp_proxy_protocol);
+    apply!(listen.proxy_protocol.tcp => tcp_proxy_protocol);
+    apply!(listen.proxy_protocol.tls => tls_proxy_protocol);
+    apply!(listen.proxy_protocol.http => http_proxy_protocol);
+
+    apply!(self.tls.cert.map(Some) => tls_cert);
+    apply!(self.tls.key.map(Some) => tls_key);
+    apply!(self.tls.reload_interval => tls_reload_interval);
+
+    apply!(self.doh.path => doh_path);
+    apply!(self.doh.json => doh_json);
+
+    let forwarding = self.forwarding.map(|sections| {
+      sections
+        .into_iter()
+        .map(|section| Forwarding {
+          name: section.zone.0,
+          upstreams: section.upstreams,
+        })
+        .collect::<Vec<_>>()
+    });
+    apply!(forwarding => forwarding);
+
+    let client_groups = self.client_groups.map(|sections| {
+      sections
+        .into_iter()
+        .map(|section| ClientGroupArg {
+          name: section.name,
+          networks: section.clients,
+        })
+        .collect::<Vec<_>>()
+    });
+    apply!(client_groups => client_groups);
+
+    apply!(self.bootstrap.resolvers => bootstrap_resolver);
+    apply!(self.bootstrap.refresh => bootstrap_refresh);
+
+    let upstream = self.upstream;
+    apply!(upstream.strategy => upstream_strategy);
+    apply!(upstream.timeout => upstream_timeout);
+    apply!(upstream.health_interval => upstream_health_interval);
+    apply!(upstream.failure_threshold => upstream_failure_threshold);
+    apply!(upstream.circuit_open => upstream_circuit_open);
+
+    let cache = self.cache;
+    apply!(cache.max_memory => cache_max_memory);
+    apply!(cache.min_ttl => cache_min_ttl);
+    apply!(cache.max_ttl => cache_max_ttl);
+    apply!(cache.negative_max_ttl => cache_negative_max_ttl);
+    apply!(cache.bypass => cache_bypass);
+    apply!(cache.serve_stale => cache_serve_stale);
+    apply!(cache.stale_ttl => cache_stale_ttl);
+    apply!(cache.stale_timeout => cache_stale_timeout);
+    apply!(cache.prefetch_min_hits => cache_prefetch_min_hits);
+    apply!(cache.prefetch_threshold => cache_prefetch_threshold);
+
+    let blocklist = self.blocklist;
+    apply!(blocklist.sources => blacklist_sources);
+    apply!(blocklist.allow => blacklist_allow);
+    apply!(blocklist.deny => blacklist_deny);
+    apply!(blocklist.bypass => blacklist_bypass);
+
+    // URL and token are only taken together, so they can't stem from
+    // different sources
+    if let Some(netbox) = self.netbox {
+      if !args::is_explicit(matches, "reverse_dns_netbox_url")
+        && !args::is_explicit(matches, "reverse_dns_netbox_token")
+      {
+        args.reverse_dns_netbox_url = Some(netbox.url.0);
+        args.reverse_dns_netbox_token = Some(netbox.token);
+      }
+    }
+
+    apply!(self.api.token.map(Some) => api_token);
+
+    apply!(self.log.filter => log_filter);
+    apply!(self.log.format => log_format);
+    apply!(self.log.target => log_target);
+
+    apply!(self.otlp.endpoint.map(Some) => otlp_endpoint);
+    apply!(self.otlp.sample_ratio => otlp_sample_ratio);
+    apply!(self.otlp.filter => otlp_filter);
+
+    let stats = self.stats;
+    apply!(stats.url.map(Some) => stats_url);
+    apply!(stats.version => stats_version);
+    apply!(stats.precision => stats_precision);
+    apply!(stats.token.map(Some) => stats_token);
+    apply!(stats.bucket.map(Some) => stats_bucket);
+    apply!(stats.org.map(Some) => stats_org);
+    apply!(stats.database.map(Some) => stats_database);
+    apply!(stats.username.map(Some) => stats_username);
+    apply!(stats.password.map(Some) => stats_password);
+    apply!(stats.buffer_capacity => stats_buffer_capacity);
+    apply!(stats.batch_size => stats_batch_size);
+    apply!(stats.overflow_policy => stats_overflow_policy);
+    apply!(stats.sample_rate => stats_sample_rate);
+    apply!(stats.flush_interval => stats_flush_interval);
+    apply!(stats.max_retries => stats_max_retries);
+    apply!(stats.initial_backoff => stats_initial_backoff);
+    apply!(stats.max_backoff => stats_max_backoff);
+    apply!(stats.spool_dir.map(Some) => stats_spool_dir);
+    apply!(stats.spool_max_size => stats_spool_max_size);
+    apply!(stats.top_capacity => stats_top_capacity);
+
+    apply!(self.query_log.capacity => query_log_capacity);
+    apply!(self.query_log.max_memory => query_log_max_memory);
+
+    let client_names = self.client_names;
+    apply!(client_names.netbox => client_names_netbox);
+    apply!(client_names.leases.map(Some) => client_names_leases);
+    apply!(client_names.resolver.map(Some) => client_names_resolver);
+    apply!(client_names.ttl => client_names_ttl);
+    apply!(client_names.negative_ttl => client_names_negative_ttl);
+    apply!(client_names.capacity => client_names_capacity);
+
+    let privacy = self.privacy;
+    apply!(privacy.client => privacy_client);
+    apply!(privacy.ipv4_prefix => privacy_ipv4_prefix);
+    apply!(privacy.ipv6_prefix => privacy_ipv6_prefix);
+    apply!(privacy.salt_rotation => privacy_salt_rotation);
+    apply!(privacy.only_blocked => privacy_only_blocked);
+    apply!(privacy.omit_query => privacy_omit_query);
+
+    apply!(self.readiness.probe.map(Some) => readiness_probe);
+    apply!(self.shutdown.timeout => shutdown_timeout);
+  }
+}
+
+/// Settings changed at runtime, applied on top of the config file until
+/// they are persisted into it. Tables are merged, anything else replaces
+/// the setting of the file.
+#[derive(Clone, Default)]
+pub(crate) struct Edits(Document);
+
+impl Edits {
+  /// Replaces the setting at the path, e.g. `["blocklist", "sources"]`.
+  pub(crate) fn set(&mut self, path: &[&str], item: Item) {
+    set(self.0.as_item_mut(), path, item);
+  }
+
+  /// The setting at the path of the file with the edits on top, `None` if
+  /// it is set in neither.
+  pub(crate) fn current(&self, file: &Path, path: &[&str]) -> anyhow::Result<Option<Item>> {
+    let mut document = read(file)?;
+    self.apply(&mut document);
+
+    Ok(get(document.as_item(), path).cloned())
+  }
+
+  /// Writes the setting at the path into the file, keeping everything else
+  /// as is, and drops it from the edits.
+  pub(crate) fn persist(&mut self, file: &Path, path: &[&str]) -> anyhow::Result<()> {
+    let Some(item) = get(self.0.as_item(), path) else {
+      return Ok(());
+    };
+
+    let mut document = read(file)?;
+    set(document.as_item_mut(), path, item.clone());
+    write(file, &document.to_string())
+      .map_err(|err| anyhow!("Unable to write config file {}: {}", file.display(), err))?;
+
+    if let Some((last, parents)) = path.split_last() {
+      let parent = parents
+        .iter()
+        .try_fold(self.0.as_item_mut(), |item, key| item.get_mut(*key));
+      if let Some(table) = parent.and_then(Item::as_table_like_mut) {
+        table.remove(last);
+      }
+    }
+
+    Ok(())
+  }
+
+  fn apply(&self, document: &mut Document) {
+    merge(document.as_item_mut(), self.0.as_item());
+  }
+}
+
+/// Writes next to the file and renames, so a failed write leaves the file
+/// as it was. The permissions are kept, as it may contain credentials.
+fn write(file: &Path, contents: &str) -> std::io::Result<()> {
+  let mut tmp = file.as_os_str().to_owned();
+  tmp.push(".tmp");
+  let tmp = PathBuf::from(tmp);
+
+  std::fs::write(&tmp, contents)?;
+  let renamed = std::fs::metadata(file)
+    .and_then(|metadata| std::fs::set_permissions(&tmp, metadata.permissions()))
+    .and_then(|()| std::fs::rename(&tmp, file));
+  if renamed.is_err() {
+    let _ = std::fs::remove_file(&tmp);
+  }
+
+  renamed
+}
+
+/// Reads the file keeping its formatting, to write it back.
+fn read(path: &Path) -> anyhow::Result<Document> {
+  std::fs::read_to_string(path)
+    .map_err(|err| anyhow!("Unable to read config file {}: {}", path.display(), err))?
+    .parse()
+    .map_err(|err| anyhow!("Invalid config file {}: {}", path.display(), err))
+}
+
+fn get<'a>(item: &'a Item, path: &[&str]) -> Option<&'a Item> {
+  path.iter().try_fold(item, |item, key| item.get(*key))
+}
+
+fn set(target: &mut Item, path: &[&str], item: Item) {
+  match path {
+    [] => *target = item,
+    [key, rest @ ..] => set(&mut target[*key], rest, item),
+  }
+}
+
+fn merge(target: &mut Item, edits: &Item) {
+  if let (Some(target), Some(edits)) = (target.as_table_like_mut(), edits.as_table_like()) {
+    for (key, edit) in edits.iter() {
+      match target.get_mut(key) {
+        Some(item) => merge(item, edit),
+        None => {
+          target.insert(key, edit.clone());
+        }
+      }
+    }
+    return;
+  }
+
+  *target = edits.clone();
+}
+
+/// Checks what parsing can't: the files referred to and settings depending
+/// on each other, which would otherwise fail at startup.
+pub(crate) fn check(args: &Args) -> anyhow::Result<()> {
+  EnvFilter::try_new(&args.log_filter)?;
+  EnvFilter::try_new(&args.otlp_filter)?;
+
+  match (&args.tls_cert, &args.tls_key) {
+    (Some(cert), Some(key)) => {
+      Certificates::load(cert.clone(), key.clone())?;
+    }
+    (None, None) => {
+      if !args.tls_listen_addr.is_empty()
+        || !args.quic_listen_addr.is_empty()
+        || !args.https_listen_addr.is_empty()
+      {
+        return Err(anyhow!(
+          "TLS, QUIC and HTTPS listeners require a certificate and key"
+        ));
+      }
+    }
+    _ => return Err(anyhow!("TLS certificate and key must be given together")),
+  }
+
+  let upstream_config = args.upstream_config();
+  for forwarding in &args.forwarding {
+    UpstreamGroup::new(
+      forwarding.name.clone(),
+      &forwarding.upstreams,
+      &upstream_config,
+    )
+    .map_err(|err| anyhow!("Invalid upstream of zone {}: {}", forwarding.name, err))?;
+  }
+
+  for (index, group) in args.client_groups.iter().enumerate() {
+    if group.name.is_empty() || group.networks.is_empty() {
+      return Err(anyhow!("Client groups require a name and networks"));
+    }
+    if args.client_groups[..index]
+      .iter()
+      .any(|other| other.name == group.name)
+    {
+      return Err(anyhow!("Duplicate client group {}", group.name));
+    }
+  }
+  for name in &args.blacklist_bypass {
+    if !args.client_groups.iter().any(|group| group.name == *name) {
+      return Err(anyhow!(
+        "Unknown client group {} to bypass the blocklist",
+        name
+      ));
+    }
+  }
+
+  if args.readiness_probe.is_some() && (args.udp_listen_addr.is_empty() || args.udp_proxy_protocol)
+  {
+    return Err(anyhow!(
+      "The readiness probe requires a UDP listener without PROXY protocol"
+    ));
+  }
+
+  if args.client_names_netbox && args.reverse_dns_netbox_url.is_none() {
+    return Err(anyhow!(
+      "Client names from NetBox require the NetBox URL and token"
+    ));
+  }
+
+  if let Some(config) = args.influx_config() {
+    Influx::new(config)?;
+  }
+
+  Ok(())
+}
+
+/// Upstream given either as on the command line, e.g.
+/// `"tls:1.1.1.1:853/cloudflare-dns.com"`, or as table.
+impl<'de> Deserialize<'de> for UpstreamDns {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    struct UpstreamVisitor;
+
+    impl<'de> Visitor<'de> for UpstreamVisitor {
+      type Value = UpstreamDns;
+
+      fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("an upstream like \"tls:1.1.1.1:853/cloudflare-dns.com\" or a table")
+      }
+
+      fn visit_str<E: de::Error>(self, value: &str) -> Result<UpstreamDns, E> {
+        value.parse().map_err(E::custom)
+      }
+
+      fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<UpstreamDns, A::Error> {
+        UpstreamTable::deserialize(MapAccessDeserializer::new(map))?
+          .upstream()
+          .map_err(de::Error::custom)
+      }
+    }
+
+    deserializer.deserialize_any(UpstreamVisitor)
+  }
+}
+
+/// Upstream as table, either at an address or by URL, e.g.
+/// `{ url = "tls://dns.internal", ca = "/etc/rdns/ca.pem" }`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamTable {
+  protocol: Option<String>,
+  address: Option<SocketAddr>,
+  /// Name the certificate of an upstream at an address is issued for.
+  name: Option<String>,
+  url: Option<String>,
+  ca: Option<String>,
+  cert: Option<String>,
+  key: Option<String>,
+  #[serde(default)]
+  pins: Vec<String>,
+  sni: Option<bool>,
+}
+
+impl UpstreamTable {
+  fn upstream(self) -> anyhow::Result<UpstreamDns> {
+    let mut upstream = match (self.url, self.protocol, self.address) {
+      (Some(url), None, None) => url.parse()?,
+      (None, Some(protocol), Some(addr)) => {
+        let encrypted =
+          |upstream: fn(SocketAddr, String, TlsOptions) -> UpstreamDns| match &self.name {
+            Some(name) => Ok(upstream(addr, name.clone(), TlsOptions::default())),
+            None => Err(anyhow!("Missing name for {} upstream", protocol)),
+          };
+
+        match protocol.as_str() {
+          "tcp" | "udp" if self.name.is_some() => {
+            return Err(anyhow!("Unexpected name for {} upstream", protocol))
+          }
+          "tcp" => UpstreamDns::Tcp(addr),
+          "udp" => UpstreamDns::Udp(addr),
+          "tls" => encrypted(UpstreamDns::Tls)?,
+          "https" => encrypted(UpstreamDns::Https)?,
+          "quic" => encrypted(UpstreamDns::Quic)?,
+          "h3" => encrypted(UpstreamDns::H3)?,
+          unknown => {
+            return Err(anyhow!(
+              "Invalid upstream protocol {}, allowed: [tcp, udp, tls, https, quic, h3]",
+              unknown
+            ))
+          }
+        }
+      }
+      _ => {
+        return Err(anyhow!(
+          "Upstream requires either url or protocol and address"
+        ))
+      }
+    };
+
+    let settings = [("ca", self.ca), ("cert", self.cert), ("key", self.key)]
+      .into_iter()
+      .filter_map(|(key, value)| Some((key, value?)))
+      .chain(self.pins.into_iter().map(|pin| ("pin", pin)))
+      .chain(self.sni.map(|sni| ("sni", sni.to_string())))
+      .collect::<Vec<_>>();
+
+    if !settings.is_empty() {
+      let options = upstream
+        .tls_options_mut()
+        .ok_or_else(|| anyhow!("TLS options require an encrypted upstream"))?;
+      for (key, value) in settings {
+        options.set(key, &value)?;
+      }
+    }
+
+    Ok(upstream)
+  }
+}
+
+/// Value parsed from a string, as clap parses arguments.
+struct Parsed<T>(T);
+
+impl<'de, T: FromStr> Deserialize<'de> for Parsed<T>
+where
+  T::Err: Display,
+{
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value
+      .parse()
+      .map(Parsed)
+      .map_err(|err| de::Error::custom(format!("invalid value {:?}: {}", value, err)))
+  }
+}
+
+fn parsed<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+  T::Err: Display,
+{
+  Ok(Some(Parsed::deserialize(deserializer)?.0))
+}
+
+fn parsed_all<'de, D: Deserializer<'de>, T: FromStr>(
+  deserializer: D,
+) -> Result<Option<Vec<T>>, D::Error>
+where
+  T::Err: Display,
+{
+  let values = Vec::<Parsed<T>>::deserialize(deserializer)?;
+  Ok(Some(values.into_iter().map(|value| value.0).collect()))
+}
+
+/// Value of an enum by the name of its argument value, e.g. `drop-oldest`.
+fn value_enum<'de, D: Deserializer<'de>, T: ValueEnum>(
+  deserializer: D,
+) -> Result<Option<T>, D::Error> {
+  let value = String::deserialize(deserializer)?;
+
+  T::from_str(&value, true).map(Some).map_err(|_| {
+    let allowed = T::value_variants()
+      .iter()
+      .filter_map(ValueEnum::to_possible_value)
+      .map(|possible| possible.get_name().to_string())
+      .collect::<Vec<_>>();
+    de::Error::custom(format!(
+      "invalid value {:?}, allowed: [{}]",
+      value,
+      allowed.join(", ")
+    ))
+  })
+}
+
+fn doh_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+  let value = String::deserialize(deserializer)?;
+  args::parse_path(&value)
+    .map(Some)
+    .map_err(de::Error::custom)
+}
+
+fn percentage<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+  bounded(deserializer, 1..=100)
+}
+
+fn ipv4_prefix<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+  bounded(deserializer, 0..=32)
+}
+
+fn ipv6_prefix<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+  bounded(deserializer, 0..=128)
+}
+
+fn bounded<'de, D: Deserializer<'de>>(
+  deserializer: D,
+  range: RangeInclusive<u8>,
+) -> Result<Option<u8>, D::Error> {
+  let value = u8::deserialize(deserializer)?;
+  if range.contains(&value) {
+    Ok(Some(value))
+  } else {
+    Err(de::Error::custom(format!(
+      "{} is not in {}..={}",
+      value,
+      range.start(),
+      range.end()
+    )))
+  }
+}
@@ -0,0 +1,149 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use trust_dns_server::proto::op::{Edns, Message, MessageType, OpCode, Query};
+use trust_dns_server::proto::rr::{Name, Record, RecordType};
+use trust_dns_server::server::RequestHandler;
+
+use crate::doh::{DohState, Params};
+
+/// JSON API as offered by Google and Cloudflare.
+const DNS_JSON: &str = "application/dns-json";
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct JsonResponse {
+  status: u16,
+  #[serde(rename = "TC")]
+  truncated: bool,
+  #[serde(rename = "RD")]
+  recursion_desired: bool,
+  #[serde(rename = "RA")]
+  recursion_available: bool,
+  #[serde(rename = "AD")]
+  authentic_data: bool,
+  #[serde(rename = "CD")]
+  checking_disabled: bool,
+  question: Vec<JsonQuestion>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  answer: Vec<JsonRecord>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  authority: Vec<JsonRecord>,
+}
+
+#[derive(Serialize)]
+struct JsonQuestion {
+  name: String,
+  #[serde(rename = "type")]
+  rtype: u16,
+}
+
+#[derive(Serialize)]
+struct JsonRecord {
+  name: String,
+  #[serde(rename = "type")]
+  rtype: u16,
+  #[serde(rename = "TTL")]
+  ttl: u32,
+  data: String,
+}
+
+pub(super) async fn respond<T: RequestHandler>(
+  state: &DohState<T>,
+  src: SocketAddr,
+  params: &Params,
+) -> Response {
+  let response = match request(params) {
+    Ok(request) => state.resolve(src, &request).await,
+    Err(status) => Err(status),
+  };
+
+  let message = match response.map(|response| Message::from_vec(&response)) {
+    Ok(Ok(message)) => message,
+    Ok(Err(_)) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    Err(status) => return status.into_response(),
+  };
+
+  // the header replaces the content type set by Json
+  ([(CONTENT_TYPE, DNS_JSON)], Json(json_response(&message))).into_response()
+}
+
+/// Encoded query for the parameters, e.g. `?name=example.com&type=AAAA`.
+fn request(params: &Params) -> Result<Vec<u8>, StatusCode> {
+  let name = params
+    .name
+    .as_deref()
+    .and_then(|name| Name::from_str(name).ok())
+    .ok_or(StatusCode::BAD_REQUEST)?;
+
+  let rtype = match params.rtype.as_deref() {
+    None => RecordType::A,
+    Some(rtype) => match rtype.parse::<u16>() {
+      Ok(rtype) => RecordType::from(rtype),
+      Err(_) => {
+        RecordType::from_str(&rtype.to_ascii_uppercase()).map_err(|_| StatusCode::BAD_REQUEST)?
+      }
+    },
+  };
+
+  let mut request = Message::new();
+  request
+    .set_id(0)
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .set_recursion_desired(true)
+    .set_checking_disabled(flag(&params.cd))
+    .add_query(Query::query(name, rtype));
+
+  if flag(&params.dnssec_ok) {
+    let mut edns = Edns::new();
+    edns.set_dnssec_ok(true);
+    request.set_edns(edns);
+  }
+
+  request
+    .to_vec()
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn json_response(message: &Message) -> JsonResponse {
+  let records = |records: &[Record]| {
+    records
+      .iter()
+      .map(|record| JsonRecord {
+        name: record.name().to_string(),
+        rtype: record.record_type().into(),
+        ttl: record.ttl(),
+        data: record.data().map(ToString::to_string).unwrap_or_default(),
+      })
+      .collect()
+  };
+
+  JsonResponse {
+    status: message.response_code().into(),
+    truncated: message.truncated(),
+    recursion_desired: message.recursion_desired(),
+    recursion_available: message.recursion_available(),
+    authentic_data: message.authentic_data(),
+    checking_disabled: message.checking_disabled(),
+    question: message
+      .queries()
+      .iter()
+      .map(|query| JsonQuestion {
+        name: query.name().to_string(),
+        rtype: query.query_type().into(),
+      })
+      .collect(),
+    answer: records(message.answers()),
+    authority: records(message.name_servers()),
+  }
+}
+
+fn flag(value: &Option<String>) -> bool {
+  matches!(value.as_deref(), Some("1" | "true"))
+}
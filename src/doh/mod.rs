@@ -0,0 +1,321 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequestParts, Query, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Router};
+use data_encoding::BASE64URL_NOPAD;
+use hyper::server::conn::Http;
+use rustls::ServerConfig;
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+use trust_dns_server::authority::MessageRequest;
+use trust_dns_server::proto::op::Message;
+use trust_dns_server::proto::rr::Record;
+use trust_dns_server::proto::serialize::binary::BinDecodable;
+use trust_dns_server::server::{Protocol, Request, RequestHandler};
+
+use crate::capture::Capture;
+use crate::proxy::{self, forwarded_client, TrustedProxies};
+use crate::shutdown::Shutdown;
+
+mod json;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct DohConfig {
+  /// Path queries are accepted at, e.g. `/dns-query`.
+  pub(crate) path: String,
+  /// Whether to answer `application/dns-json` queries.
+  pub(crate) json: bool,
+}
+
+/// Peer of a connection, `proxies` is set if the peer is a trusted proxy
+/// whose forwarding headers are believed.
+#[derive(Clone)]
+struct Connection {
+  peer: SocketAddr,
+  proxies: Option<TrustedProxies>,
+}
+
+/// Address of the client of a request.
+struct ClientAddr(SocketAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddr {
+  type Rejection = StatusCode;
+
+  async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+    let connection = parts
+      .extensions
+      .get::<Connection>()
+      .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let forwarded = connection
+      .proxies
+      .as_ref()
+      .and_then(|proxies| forwarded_client(&parts.headers, proxies));
+
+    // the port of the client is not forwarded
+    Ok(ClientAddr(match forwarded {
+      Some(ip) => SocketAddr::new(ip, 0),
+      None => connection.peer,
+    }))
+  }
+}
+
+struct DohState<T> {
+  handler: Arc<T>,
+  json: bool,
+}
+
+// derive(Clone) would require T: Clone
+impl<T> Clone for DohState<T> {
+  fn clone(&self) -> Self {
+    Self {
+      handler: self.handler.clone(),
+      json: self.json,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct Params {
+  /// Base64url encoded query (RFC 8484 4.1).
+  dns: Option<String>,
+  name: Option<String>,
+  #[serde(rename = "type")]
+  rtype: Option<String>,
+  #[serde(rename = "do")]
+  dnssec_ok: Option<String>,
+  cd: Option<String>,
+}
+
+/// DNS over HTTPS (RFC 8484) endpoint answering queries through the handler.
+pub(crate) fn router<T: RequestHandler>(handler: T, config: &DohConfig) -> Router {
+  Router::new()
+    .route(&config.path, get(query_get::<T>).post(query_post::<T>))
+    .with_state(DohState {
+      handler: Arc::new(handler),
+      json: config.json,
+    })
+}
+
+/// Serves the router to every client completing a TLS handshake.
+pub(crate) async fn serve_tls(
+  listener: TcpListener,
+  tls_config: Arc<ServerConfig>,
+  router: Router,
+  shutdown: Shutdown,
+) {
+  let acceptor = TlsAcceptor::from(tls_config);
+
+  loop {
+    let accepted = select! {
+      accepted = listener.accept() => accepted,
+      _ = shutdown.triggered() => return,
+    };
+
+    let (stream, src) = match accepted {
+      Ok(accepted) => accepted,
+      Err(err) => {
+        debug!("Unable to accept connection: {}", err);
+        continue;
+      }
+    };
+
+    let acceptor = acceptor.clone();
+    let service = router.clone().layer(Extension(Connection {
+      peer: src,
+      proxies: None,
+    }));
+    let shutdown = shutdown.clone();
+
+    tokio::spawn(async move {
+      let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+          debug!("TLS handshake with {} failed: {}", src, err);
+          return;
+        }
+        Err(_) => {
+          debug!("TLS handshake with {} timed out", src);
+          return;
+        }
+      };
+
+      if let Err(err) = serve_connection(stream, service, &shutdown).await {
+        debug!("Connection to {} failed: {}", src, err);
+      }
+    });
+  }
+}
+
+/// Serves the router over plain HTTP to a reverse proxy terminating TLS.
+/// Trusted proxies may send a PROXY protocol header and forwarding headers
+/// with the address of the client.
+pub(crate) async fn serve_http(
+  listener: TcpListener,
+  router: Router,
+  proxies: TrustedProxies,
+  proxy_protocol: bool,
+  shutdown: Shutdown,
+) {
+  loop {
+    let accepted = select! {
+      accepted = listener.accept() => accepted,
+      _ = shutdown.triggered() => return,
+    };
+
+    let (mut stream, peer) = match accepted {
+      Ok(accepted) => accepted,
+      Err(err) => {
+        debug!("Unable to accept connection: {}", err);
+        continue;
+      }
+    };
+
+    let router = router.clone();
+    let proxies = proxies.clone();
+    let shutdown = shutdown.clone();
+
+    tokio::spawn(async move {
+      let mut src = peer;
+      if proxy_protocol && proxies.contains(peer.ip()) {
+        match timeout(HANDSHAKE_TIMEOUT, proxy::read_header(&mut stream)).await {
+          Ok(Ok(header)) => src = header.unwrap_or(peer),
+          Ok(Err(err)) => {
+            debug!("Invalid PROXY protocol header from {}: {}", peer, err);
+            return;
+          }
+          Err(_) => {
+            debug!("PROXY protocol header from {} timed out", peer);
+            return;
+          }
+        }
+      }
+
+      // forwarding headers are only believed from the hop sending the
+      // requests, with the PROXY protocol the one in front of the proxy
+      let service = router.layer(Extension(Connection {
+        peer: src,
+        proxies: proxies.contains(src.ip()).then_some(proxies),
+      }));
+
+      if let Err(err) = serve_connection(stream, service, &shutdown).await {
+        debug!("Connection to {} failed: {}", src, err);
+      }
+    });
+  }
+}
+
+/// Serves requests of the connection until the client closes it. On
+/// shutdown, requests in progress are completed before it is closed.
+async fn serve_connection<S>(stream: S, service: Router, shutdown: &Shutdown) -> hyper::Result<()>
+where
+  S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  let connection = Http::new().serve_connection(stream, service);
+  tokio::pin!(connection);
+
+  select! {
+    result = connection.as_mut() => result,
+    _ = shutdown.triggered() => {
+      connection.as_mut().graceful_shutdown();
+      connection.await
+    }
+  }
+}
+
+async fn query_get<T: RequestHandler>(
+  State(state): State<DohState<T>>,
+  ClientAddr(src): ClientAddr,
+  Query(params): Query<Params>,
+) -> Response {
+  if let Some(dns) = &params.dns {
+    // padding is not allowed, but tolerated
+    return match BASE64URL_NOPAD.decode(dns.trim_end_matches('=').as_bytes()) {
+      Ok(wire) => state.respond(src, &wire).await,
+      Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    };
+  }
+
+  if state.json && params.name.is_some() {
+    return json::respond(&state, src, &params).await;
+  }
+
+  StatusCode::BAD_REQUEST.into_response()
+}
+
+async fn query_post<T: RequestHandler>(
+  State(state): State<DohState<T>>,
+  ClientAddr(src): ClientAddr,
+  headers: HeaderMap,
+  body: Bytes,
+) -> Response {
+  if headers
+    .get(CONTENT_TYPE)
+    .map_or(true, |content_type| content_type != DNS_MESSAGE)
+  {
+    return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+  }
+
+  state.respond(src, &body).await
+}
+
+impl<T: RequestHandler> DohState<T> {
+  async fn respond(&self, src: SocketAddr, wire: &[u8]) -> Response {
+    let response = match self.resolve(src, wire).await {
+      Ok(response) => response,
+      Err(status) => return status.into_response(),
+    };
+
+    let max_age = Message::from_vec(&response)
+      .ok()
+      .and_then(|message| max_age(&message));
+
+    let mut response = ([(CONTENT_TYPE, DNS_MESSAGE)], response).into_response();
+    if let Some(max_age) = max_age {
+      let value = format!("max-age={}", max_age);
+      if let Ok(value) = value.parse() {
+        response.headers_mut().insert(CACHE_CONTROL, value);
+      }
+    }
+
+    response
+  }
+
+  /// Encoded response to the encoded query.
+  async fn resolve(&self, src: SocketAddr, wire: &[u8]) -> Result<Vec<u8>, StatusCode> {
+    let message = MessageRequest::from_bytes(wire).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let request = Request::new(message, src, Protocol::Https);
+
+    let capture = Capture::default();
+    self.handler.handle_request(&request, capture.clone()).await;
+
+    capture.take().ok_or(StatusCode::INTERNAL_SERVER_ERROR)
+  }
+}
+
+/// Freshness lifetime of the response, the lowest TTL (RFC 8484 5.1).
+fn max_age(message: &Message) -> Option<u32> {
+  message
+    .answers()
+    .iter()
+    .chain(message.name_servers())
+    .map(Record::ttl)
+    .min()
+}
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use quinn::{Connecting, Endpoint, RecvStream, SendStream};
+use rustls::ServerConfig;
+use tokio::select;
+use tracing::debug;
+use trust_dns_server::authority::MessageRequest;
+use trust_dns_server::proto::serialize::binary::BinDecodable;
+use trust_dns_server::server::{Protocol, Request, RequestHandler};
+
+use crate::capture::Capture;
+use crate::shutdown::Shutdown;
+
+/// Upper bound of a length prefixed DNS message.
+const MAX_MESSAGE_SIZE: usize = 2 + u16::MAX as usize;
+
+/// DNS over QUIC (RFC 9250) endpoint bound to the address.
+pub(crate) fn bind(addr: SocketAddr, tls_config: Arc<ServerConfig>) -> anyhow::Result<Endpoint> {
+  Ok(Endpoint::server(
+    quinn::ServerConfig::with_crypto(tls_config),
+    addr,
+  )?)
+}
+
+/// Answers queries of every connection to the endpoint through the handler.
+/// On shutdown no more connections and streams are accepted, connections
+/// close once their last stream is answered.
+pub(crate) async fn serve<T: RequestHandler>(
+  endpoint: Endpoint,
+  handler: Arc<T>,
+  shutdown: Shutdown,
+) {
+  loop {
+    let connecting = select! {
+      connecting = endpoint.accept() => connecting,
+      _ = shutdown.triggered() => return,
+    };
+
+    let Some(connecting) = connecting else {
+      return;
+    };
+    tokio::spawn(connection(connecting, handler.clone(), shutdown.clone()));
+  }
+}
+
+async fn connection<T: RequestHandler>(
+  connecting: Connecting,
+  handler: Arc<T>,
+  shutdown: Shutdown,
+) {
+  let connection = match connecting.await {
+    Ok(connection) => connection,
+    Err(err) => {
+      debug!("QUIC handshake failed: {}", err);
+      return;
+    }
+  };
+  let src = connection.remote_address();
+
+  // every query comes on its own stream
+  loop {
+    let (send, recv) = select! {
+      accepted = connection.accept_bi() => match accepted {
+        Ok(accepted) => accepted,
+        Err(_) => return,
+      },
+      _ = shutdown.triggered() => return,
+    };
+
+    let handler = handler.clone();
+    tokio::spawn(async move {
+      if let Err(err) = stream(&*handler, src, send, recv).await {
+        debug!("QUIC stream of {} failed: {}", src, err);
+      }
+    });
+  }
+}
+
+async fn stream<T: RequestHandler>(
+  handler: &T,
+  src: SocketAddr,
+  mut send: SendStream,
+  recv: RecvStream,
+) -> anyhow::Result<()> {
+  let query = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+  let message = length_prefixed(&query).ok_or_else(|| anyhow!("Invalid length prefix"))?;
+  let request = Request::new(MessageRequest::from_bytes(message)?, src, Protocol::Quic);
+
+  let capture = Capture::default();
+  handler.handle_request(&request, capture.clone()).await;
+  let response = capture
+    .take()
+    .ok_or_else(|| anyhow!("Handler did not send a response"))?;
+
+  let len = u16::try_from(response.len())?;
+  send.write_all(&len.to_be_bytes()).await?;
+  send.write_all(&response).await?;
+  send.finish().await?;
+
+  Ok(())
+}
+
+fn length_prefixed(buf: &[u8]) -> Option<&[u8]> {
+  let len = usize::from(u16::from_be_bytes([*buf.first()?, *buf.get(1)?]));
+  let message = &buf[2..];
+  (message.len() == len).then_some(message)
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::fs;
+use tokio::time::timeout;
+use tracing::debug;
+use trust_dns_server::resolver::config::{
+  NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
+};
+use trust_dns_server::resolver::TokioAsyncResolver;
+
+use crate::authority::netbox::NetboxClient;
+
+/// Upper bound of a lookup through all sources.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct HostnamesConfig {
+  pub(crate) netbox: Option<Arc<NetboxClient>>,
+  /// dnsmasq style leases file (`<expiry> <mac> <ip> <hostname> <client-id>`).
+  pub(crate) leases: Option<PathBuf>,
+  /// Address rdns itself listens on, used for PTR lookups.
+  pub(crate) resolver: Option<SocketAddr>,
+  pub(crate) ttl: Duration,
+  pub(crate) negative_ttl: Duration,
+  pub(crate) capacity: usize,
+}
+
+/// Resolves client addresses to hostnames for stats and logs.
+///
+/// Lookups never block the request path: a cache miss returns no name and
+/// resolves it in the background, expired names are served until the
+/// refresh completes. Sources are tried in order: DHCP leases, NetBox and
+/// finally a PTR lookup.
+#[derive(Clone)]
+pub(crate) struct Hostnames(Arc<Inner>);
+
+struct Inner {
+  netbox: Option<Arc<NetboxClient>>,
+  leases: Option<PathBuf>,
+  resolver: Option<TokioAsyncResolver>,
+  ttl: Duration,
+  negative_ttl: Duration,
+  capacity: usize,
+  cache: Mutex<HashMap<IpAddr, Cached>>,
+}
+
+struct Cached {
+  name: Option<String>,
+  expires: Instant,
+  refreshing: bool,
+}
+
+impl Hostnames {
+  pub(crate) fn new(config: HostnamesConfig) -> Self {
+    let resolver = config.resolver.map(|addr| {
+      let name_servers = NameServerConfigGroup::from(vec![NameServerConfig {
+        socket_addr: addr,
+        protocol: Protocol::Udp,
+        tls_dns_name: None,
+        trust_negative_responses: true,
+        tls_config: None,
+        bind_addr: None,
+      }]);
+
+      TokioAsyncResolver::tokio(
+        ResolverConfig::from_parts(None, vec![], name_servers),
+        ResolverOpts::default(),
+      )
+    });
+
+    Self(Arc::new(Inner {
+      netbox: config.netbox,
+      leases: config.leases,
+      resolver,
+      ttl: config.ttl,
+      negative_ttl: config.negative_ttl,
+      capacity: config.capacity.max(1),
+      cache: Mutex::new(HashMap::new()),
+    }))
+  }
+
+  /// Returns the cached name of the client, if any, and triggers a
+  /// background lookup if it is unknown or expired.
+  pub(crate) fn get(&self, ip: IpAddr) -> Option<String> {
+    let now = Instant::now();
+    let mut cache = self.0.cache.lock().unwrap();
+
+    if let Some(cached) = cache.get_mut(&ip) {
+      if cached.expires <= now && !cached.refreshing {
+        cached.refreshing = true;
+        self.refresh(ip);
+      }
+      return cached.name.clone();
+    }
+
+    if cache.len() >= self.0.capacity {
+      cache.retain(|_, cached| cached.expires > now || cached.refreshing);
+      if cache.len() >= self.0.capacity {
+        cache.clear();
+      }
+    }
+
+    cache.insert(
+      ip,
+      Cached {
+        name: None,
+        expires: now,
+        refreshing: true,
+      },
+    );
+    self.refresh(ip);
+
+    None
+  }
+
+  fn refresh(&self, ip: IpAddr) {
+    let refresh = Refresh {
+      hostnames: self.clone(),
+      ip,
+      done: false,
+    };
+
+    tokio::spawn(async move {
+      let name = match timeout(LOOKUP_TIMEOUT, refresh.hostnames.resolve(ip)).await {
+        Ok(name) => name,
+        Err(_) => {
+          debug!("Looking up the name of {} timed out", ip);
+          None
+        }
+      };
+      refresh.finish(name);
+    });
+  }
+
+  fn store(&self, ip: IpAddr, name: Option<String>) {
+    let ttl = if name.is_some() {
+      self.0.ttl
+    } else {
+      self.0.negative_ttl
+    };
+
+    // not poisoned by a panicking lookup, which never holds the lock
+    if let Ok(mut cache) = self.0.cache.lock() {
+      cache.insert(
+        ip,
+        Cached {
+          name,
+          expires: Instant::now() + ttl,
+          refreshing: false,
+        },
+      );
+    }
+  }
+
+  async fn resolve(&self, ip: IpAddr) -> Option<String> {
+    if let Some(path) = &self.0.leases {
+      match fs::read_to_string(path).await {
+        Ok(leases) => {
+          if let Some(name) = find_lease(&leases, ip) {
+            return Some(name);
+          }
+        }
+        Err(err) => debug!("Unable to read leases {}: {}", path.display(), err),
+      }
+    }
+
+    if let Some(netbox) = &self.0.netbox {
+      match netbox.lookup(ip).await {
+        Ok(names) => {
+          if let Some(name) = names.first() {
+            return Some(trim_name(name.to_string()));
+          }
+        }
+        Err(err) => debug!("Unable to look up {} in netbox: {}", ip, err),
+      }
+    }
+
+    if let Some(resolver) = &self.0.resolver {
+      match resolver.reverse_lookup(ip).await {
+        Ok(lookup) => {
+          if let Some(name) = lookup.iter().next() {
+            return Some(trim_name(name.to_string()));
+          }
+        }
+        Err(err) => debug!("Unable to resolve PTR of {}: {}", ip, err),
+      }
+    }
+
+    None
+  }
+}
+
+/// A lookup in flight, which caches no name unless it finishes, so the
+/// address is looked up again after the negative TTL even if it panicked.
+struct Refresh {
+  hostnames: Hostnames,
+  ip: IpAddr,
+  done: bool,
+}
+
+impl Refresh {
+  fn finish(mut self, name: Option<String>) {
+    self.done = true;
+    self.hostnames.store(self.ip, name);
+  }
+}
+
+impl Drop for Refresh {
+  fn drop(&mut self) {
+    if !self.done {
+      self.hostnames.store(self.ip, None);
+    }
+  }
+}
+
+fn find_lease(leases: &str, ip: IpAddr) -> Option<String> {
+  leases.lines().find_map(|line| {
+    let mut fields = line.split_whitespace().skip(2);
+    let lease_ip = fields.next()?.parse::<IpAddr>().ok()?;
+    let name = fields.next()?;
+
+    (lease_ip == ip && name != "*").then(|| name.to_string())
+  })
+}
+
+fn trim_name(name: String) -> String {
+  match name.strip_suffix('.') {
+    Some(name) => name.to_string(),
+    None => name,
+  }
+}
@@ -0,0 +1,147 @@
+use clap::ValueEnum;
+use tracing_subscriber::fmt;
+use tracing_subscriber::{Layer, Registry};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum LogFormat {
+  Compact,
+  Full,
+  Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum LogTarget {
+  Stdout,
+  /// systemd journal, with structured fields.
+  Journald,
+  /// Local syslog daemon via `/dev/log`.
+  Syslog,
+}
+
+/// Layer writing log events in the given format to the given target.
+/// Journald has its own structured format, so `format` only applies to
+/// stdout and syslog.
+pub(crate) fn layer(
+  format: LogFormat,
+  target: LogTarget,
+) -> anyhow::Result<Box<dyn Layer<Registry> + Send + Sync>> {
+  Ok(match target {
+    LogTarget::Stdout => match format {
+      LogFormat::Compact => fmt::layer().compact().boxed(),
+      LogFormat::Full => fmt::layer().boxed(),
+      LogFormat::Json => fmt::layer().json().boxed(),
+    },
+    #[cfg(unix)]
+    LogTarget::Journald => tracing_journald::layer()?.boxed(),
+    #[cfg(unix)]
+    LogTarget::Syslog => {
+      let layer = fmt::layer()
+        .with_ansi(false)
+        .without_time()
+        .with_writer(syslog::Syslog::connect()?);
+
+      match format {
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+      }
+    }
+    #[cfg(not(unix))]
+    LogTarget::Journald | LogTarget::Syslog => {
+      return Err(anyhow::anyhow!("Log target is only supported on unix"));
+    }
+  })
+}
+
+#[cfg(unix)]
+mod syslog {
+  use std::io::Write;
+  use std::os::unix::net::UnixDatagram;
+
+  use tracing::{Level, Metadata};
+  use tracing_subscriber::fmt::MakeWriter;
+
+  const SOCKET: &str = "/dev/log";
+  const FACILITY_DAEMON: u8 = 3;
+
+  /// Sends every event as a single RFC 3164 datagram to the local syslog daemon.
+  pub(super) struct Syslog {
+    socket: UnixDatagram,
+    header: String,
+  }
+
+  impl Syslog {
+    pub(super) fn connect() -> std::io::Result<Self> {
+      let socket = UnixDatagram::unbound()?;
+      socket.connect(SOCKET)?;
+
+      Ok(Self {
+        socket,
+        header: format!("{}[{}]: ", env!("CARGO_PKG_NAME"), std::process::id()),
+      })
+    }
+  }
+
+  pub(super) struct SyslogWriter<'a> {
+    syslog: &'a Syslog,
+    severity: u8,
+    buf: Vec<u8>,
+  }
+
+  impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+      SyslogWriter {
+        syslog: self,
+        severity: 6,
+        buf: Vec::new(),
+      }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+      let severity = match *meta.level() {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+      };
+
+      SyslogWriter {
+        severity,
+        ..self.make_writer()
+      }
+    }
+  }
+
+  impl Write for SyslogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.buf.extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Drop for SyslogWriter<'_> {
+    fn drop(&mut self) {
+      let message = String::from_utf8_lossy(&self.buf);
+      let message = message.trim_end();
+      if message.is_empty() {
+        return;
+      }
+
+      let datagram = format!(
+        "<{}>{}{}",
+        FACILITY_DAEMON * 8 + self.severity,
+        self.syslog.header,
+        message
+      );
+
+      // there is nowhere left to report a failure to log
+      let _ = self.syslog.socket.send(datagram.as_bytes());
+    }
+  }
+}
@@ -1,39 +1,90 @@
-use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use clap::Parser;
+use anyhow::anyhow;
+use clap::CommandFactory;
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::select;
 use tokio::signal::ctrl_c;
-use tracing::{error, info, Level};
-use tracing_subscriber::FmtSubscriber;
-use trust_dns_server::authority::{Authority, Catalog, ZoneType};
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, warn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
 use trust_dns_server::proto::rr::LowerName;
-use trust_dns_server::resolver::config::{NameServerConfig, NameServerConfigGroup, Protocol};
-use trust_dns_server::store::forwarder::{ForwardAuthority, ForwardConfig};
 use trust_dns_server::ServerFuture;
 
-use crate::args::{Args, UpstreamDns};
-use crate::authority::netbox::{NetboxClient, NetboxIpv4Authority};
+use crate::api::ApiState;
+use crate::args::{Args, Command};
+use crate::authority::netbox::NetboxClient;
 use crate::blacklist::Blacklist;
-use crate::stats::Stats;
+use crate::cache::{CacheConfig, CachingHandler, ResponseCache};
+use crate::certs::Certificates;
+use crate::client_groups::ClientGroups;
+use crate::config::Edits;
+use crate::doh::DohConfig;
+use crate::hostnames::{Hostnames, HostnamesConfig};
+use crate::privacy::{Privacy, PrivacyConfig};
+use crate::proxied::ProxiedServer;
+use crate::proxy::TrustedProxies;
+use crate::readiness::Readiness;
+use crate::reload::{Reloader, Swap};
+use crate::shutdown::{Shutdown, Tracked};
+use crate::stats::aggregate::Aggregates;
+use crate::stats::query_log::QueryLog;
+use crate::stats::{BufferConfig, Recorders, Stats};
+use crate::zones::Zones;
 
+mod api;
 mod args;
 mod authority;
 mod blacklist;
+mod cache;
+mod capture;
+mod certs;
+mod client_groups;
+mod config;
+mod doh;
+mod doq;
+mod hostnames;
+mod logging;
+mod privacy;
+mod proxied;
+mod proxy;
+mod readiness;
+mod reload;
+mod shutdown;
 mod stats;
+mod telemetry;
+mod upstream;
+mod zones;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-  let args = Args::parse();
+  let matches = Args::command().get_matches();
+  let args = Args::from_matches(&matches, &Edits::default())?;
 
-  let subscriber = FmtSubscriber::builder()
-    .with_max_level(Level::DEBUG)
-    .compact()
-    .finish();
+  if let Some(Command::CheckConfig) = args.command {
+    config::check(&args)?;
+    println!("Configuration is valid");
+    return Ok(());
+  }
+
+  // filtered separately, so spans can be exported below the log level
+  let otlp = match &args.otlp_endpoint {
+    Some(endpoint) => Some(
+      telemetry::otlp_layer(endpoint, args.otlp_sample_ratio)?
+        .with_filter(EnvFilter::try_new(&args.otlp_filter)?),
+    ),
+    None => None,
+  };
 
-  tracing::subscriber::set_global_default(subscriber)?;
+  tracing_subscriber::registry()
+    .with(
+      logging::layer(args.log_format, args.log_target)?
+        .with_filter(EnvFilter::try_new(&args.log_filter)?),
+    )
+    .with(otlp)
+    .try_init()?;
 
   info!(concat!(
     "Booting ",
@@ -43,119 +94,327 @@ async fn main() -> anyhow::Result<()> {
     "..."
   ));
 
-  let mut catalog = Catalog::new();
-
-  for forwarding in args.forwarding {
-    let upstreams = forwarding
-      .upstreams
-      .iter()
-      .map(|upstream| match upstream {
-        UpstreamDns::Tcp(addr) => NameServerConfig {
-          socket_addr: *addr,
-          protocol: Protocol::Tcp,
-          tls_dns_name: None,
-          trust_negative_responses: true,
-          tls_config: None,
-          bind_addr: None,
-        },
-        UpstreamDns::Udp(addr) => NameServerConfig {
-          socket_addr: *addr,
-          protocol: Protocol::Udp,
-          tls_dns_name: None,
-          trust_negative_responses: true,
-          tls_config: None,
-          bind_addr: None,
-        },
-        UpstreamDns::Tls(addr, domain) => NameServerConfig {
-          socket_addr: *addr,
-          protocol: Protocol::Tls,
-          tls_dns_name: Some(domain.to_string()),
-          trust_negative_responses: true,
-          tls_config: None,
-          bind_addr: None,
-        },
-        UpstreamDns::Https(addr, domain) => NameServerConfig {
-          socket_addr: *addr,
-          protocol: Protocol::Https,
-          tls_dns_name: Some(domain.to_string()),
-          trust_negative_responses: true,
-          tls_config: None,
-          bind_addr: None,
-        },
-      })
-      .collect::<Vec<_>>();
-
-    let authority = ForwardAuthority::try_from_config(
-      forwarding.name,
-      ZoneType::Forward,
-      &ForwardConfig {
-        name_servers: NameServerConfigGroup::from(upstreams),
-        options: None,
-      },
-    )
-    .unwrap();
+  let zones = Swap::new(Zones::build(&args).await?);
+  // empty until the initial load below completes
+  let blacklist = Swap::new(
+    Blacklist::with_sources(Vec::new())
+      .with_exceptions(&args.blacklist_allow, &args.blacklist_deny),
+  );
+  let client_groups = Swap::new(ClientGroups::new(
+    &args.client_groups,
+    &args.blacklist_bypass,
+  ));
+  let sink = Swap::new(reload::sink(&args, None).await?);
 
-    catalog.upsert(authority.origin().clone(), Box::new(Arc::new(authority)))
-  }
+  let reloader = Arc::new(Reloader::new(
+    matches,
+    zones.clone(),
+    blacklist.clone(),
+    args.blacklist_sources.clone(),
+    client_groups.clone(),
+    sink.clone(),
+  ));
 
-  if let Some(url) = args.reverse_dns_netbox_url {
-    info!("Configuring netbox");
-    let netbox_client = Arc::new(NetboxClient::new(
-      url,
-      args.reverse_dns_netbox_token.unwrap(),
-    ));
+  #[cfg(unix)]
+  {
+    let reloader = reloader.clone();
+    tokio::spawn(async move { reloader.on_hangup().await });
+  }
 
-    catalog.upsert(
-      LowerName::from_str("in-addr.arpa.")?,
-      Box::new(NetboxIpv4Authority::new(netbox_client)),
-    );
+  {
+    let reloader = reloader.clone();
+    tokio::spawn(async move {
+      // queries are answered unfiltered meanwhile, readiness reports it
+      while let Err(err) = reloader.load_blacklist().await {
+        error!("Unable to load blacklist: {}", err);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+      }
+    });
   }
 
-  let mut blacklist = Blacklist::new();
-  blacklist.update().await?;
+  let readiness = Arc::new(Readiness::new(
+    zones.clone(),
+    blacklist.clone(),
+    args.readiness_probe.clone(),
+  ));
+
+  let netbox_client = match (&args.reverse_dns_netbox_url, &args.reverse_dns_netbox_token) {
+    (Some(url), Some(token)) if args.client_names_netbox => {
+      Some(Arc::new(NetboxClient::new(url.clone(), token.clone())))
+    }
+    _ => None,
+  };
+
+  let hostnames = if args.client_names_netbox
+    || args.client_names_leases.is_some()
+    || args.client_names_resolver.is_some()
+  {
+    Some(Hostnames::new(HostnamesConfig {
+      netbox: netbox_client,
+      leases: args.client_names_leases,
+      resolver: args.client_names_resolver,
+      ttl: Duration::from_secs(args.client_names_ttl),
+      negative_ttl: Duration::from_secs(args.client_names_negative_ttl),
+      capacity: args.client_names_capacity,
+    }))
+  } else {
+    None
+  };
+
+  let recorders = Recorders {
+    aggregates: Arc::new(Aggregates::new(args.stats_top_capacity)),
+    query_log: (args.query_log_capacity > 0).then(|| {
+      Arc::new(QueryLog::new(
+        args.query_log_capacity,
+        args.query_log_max_memory,
+      ))
+    }),
+  };
+
+  let cache = Arc::new(ResponseCache::new(CacheConfig {
+    max_memory: args.cache_max_memory,
+    min_ttl: Duration::from_secs(args.cache_min_ttl),
+    max_ttl: Duration::from_secs(args.cache_max_ttl),
+    negative_max_ttl: Duration::from_secs(args.cache_negative_max_ttl),
+    bypass: args.cache_bypass.into_iter().map(LowerName::from).collect(),
+    serve_stale: Duration::from_secs(args.cache_serve_stale),
+    stale_ttl: Duration::from_secs(args.cache_stale_ttl),
+    stale_timeout: Duration::from_millis(args.cache_stale_timeout),
+    prefetch_min_hits: args.cache_prefetch_min_hits,
+    prefetch_threshold: args.cache_prefetch_threshold,
+  }));
+
+  let shutdown = Shutdown::new();
 
   let stats = Stats::new(
-    args.stats_url.as_ref().unwrap(),
-    args.stats_bucket.unwrap(),
-    args.stats_org.unwrap(),
-    args.stats_token.as_ref().unwrap(),
-    catalog,
-    blacklist,
+    CachingHandler::new(cache.clone(), zones.clone()),
+    blacklist.clone(),
+    client_groups.clone(),
+    sink,
+    BufferConfig {
+      capacity: args.stats_buffer_capacity,
+      batch_size: args.stats_batch_size,
+      overflow_policy: args.stats_overflow_policy,
+      sample_rate: args.stats_sample_rate,
+      flush_interval: Duration::from_secs(args.stats_flush_interval),
+      max_retries: args.stats_max_retries,
+      initial_backoff: Duration::from_millis(args.stats_initial_backoff),
+      max_backoff: Duration::from_millis(args.stats_max_backoff),
+    },
+    Privacy::new(PrivacyConfig {
+      client: args.privacy_client,
+      ipv4_prefix: args.privacy_ipv4_prefix,
+      ipv6_prefix: args.privacy_ipv6_prefix,
+      salt_rotation: Duration::from_secs(args.privacy_salt_rotation),
+      only_blocked: args.privacy_only_blocked,
+      omit_query: args.privacy_omit_query,
+    }),
+    recorders.clone(),
+    hostnames,
+    shutdown.clone(),
+    readiness.clone(),
   );
 
-  let mut server = ServerFuture::new(stats.clone());
+  // serves health and readiness while the listeners are bound
+  if let Some(addr) = args.api_listen_addr {
+    let router = api::router(ApiState {
+      recorders,
+      blacklist,
+      client_groups,
+      cache,
+      zones,
+      reloader,
+      readiness: readiness.clone(),
+      admin_token: args.api_token.map(Arc::from),
+    });
+
+    let listener = TcpListener::bind(addr).await?;
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+      if let Err(err) = api::serve(listener, router, shutdown).await {
+        error!("Unable to serve api: {}", err);
+      }
+    });
+    info!("API listening on {}/tcp...", addr);
+  }
+
+  let mut server = ServerFuture::new(Tracked::new(stats.clone(), shutdown.clone()));
+  let handler = Arc::new(Tracked::new(stats.clone(), shutdown.clone()));
+  let proxies = TrustedProxies::new(args.trusted_proxies);
+  let proxied = ProxiedServer::new(handler.clone(), proxies.clone(), shutdown.clone());
+  // without any, block_until_done would complete right away
+  let mut server_listeners = false;
+  let mut probe_addr = None;
 
   for addr in args.udp_listen_addr {
     let udp = UdpSocket::bind(addr).await?;
-    server.register_socket(udp);
+    if args.udp_proxy_protocol {
+      tokio::spawn(proxied.clone().serve_udp(udp));
+    } else {
+      probe_addr.get_or_insert(udp.local_addr()?);
+      server.register_socket(udp);
+      server_listeners = true;
+    }
     info!("Listening on {}/udp...", addr);
   }
 
+  if args.readiness_probe.is_some() && probe_addr.is_none() {
+    return Err(anyhow!(
+      "The readiness probe requires a UDP listener without PROXY protocol"
+    ));
+  }
+
   for addr in args.tcp_listen_addr {
     let tcp = TcpListener::bind(addr).await?;
-    server.register_listener(tcp, Duration::from_secs(10));
+    if args.tcp_proxy_protocol {
+      tokio::spawn(proxied.clone().serve_tcp(tcp, None));
+    } else {
+      server.register_listener(tcp, Duration::from_secs(10));
+      server_listeners = true;
+    }
     info!("Listening on {}/tcp...", addr);
   }
 
-  tokio::spawn(async move {
-    loop {
-      if let Err(err) = stats.flush().await {
-        error!("Error: {}", err);
+  let certificates = match (args.tls_cert, args.tls_key) {
+    (Some(cert), Some(key)) => {
+      let certificates = Arc::new(Certificates::load(cert, key)?);
+
+      if args.tls_reload_interval > 0 {
+        let watched = certificates.clone();
+        let interval = Duration::from_secs(args.tls_reload_interval);
+        tokio::spawn(async move { watched.watch(interval).await });
       }
-      tokio::time::sleep(Duration::from_secs(10)).await;
+
+      Some(certificates)
     }
-  });
+    (None, None) => None,
+    _ => return Err(anyhow!("TLS certificate and key must be given together")),
+  };
+
+  if !args.tls_listen_addr.is_empty() {
+    let tls_config = certificates
+      .as_ref()
+      .ok_or_else(|| anyhow!("TLS listeners require a certificate and key"))?
+      .server_config(&[]);
+
+    for addr in args.tls_listen_addr {
+      let tcp = TcpListener::bind(addr).await?;
+      if args.tls_proxy_protocol {
+        tokio::spawn(proxied.clone().serve_tcp(tcp, Some(tls_config.clone())));
+      } else {
+        server.register_tls_listener_with_tls_config(
+          tcp,
+          Duration::from_secs(10),
+          tls_config.clone(),
+        )?;
+        server_listeners = true;
+      }
+      info!("Listening on {}/tls...", addr);
+    }
+  }
+
+  if !args.quic_listen_addr.is_empty() {
+    let tls_config = certificates
+      .as_ref()
+      .ok_or_else(|| anyhow!("QUIC listeners require a certificate and key"))?
+      .server_config(&[b"doq"]);
+
+    for addr in args.quic_listen_addr {
+      let endpoint = doq::bind(addr, tls_config.clone())?;
+      tokio::spawn(doq::serve(endpoint, handler.clone(), shutdown.clone()));
+      info!("Listening on {}/quic...", addr);
+    }
+  }
+
+  let router = doh::router(
+    Tracked::new(stats.clone(), shutdown.clone()),
+    &DohConfig {
+      path: args.doh_path,
+      json: args.doh_json,
+    },
+  );
+
+  if !args.https_listen_addr.is_empty() {
+    let tls_config = certificates
+      .as_ref()
+      .ok_or_else(|| anyhow!("HTTPS listeners require a certificate and key"))?
+      .server_config(&[b"h2", b"http/1.1"]);
+
+    for addr in args.https_listen_addr {
+      let listener = TcpListener::bind(addr).await?;
+      tokio::spawn(doh::serve_tls(
+        listener,
+        tls_config.clone(),
+        router.clone(),
+        shutdown.clone(),
+      ));
+      info!("Listening on {}/https...", addr);
+    }
+  }
+
+  for addr in args.http_listen_addr {
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(doh::serve_http(
+      listener,
+      router.clone(),
+      proxies.clone(),
+      args.http_proxy_protocol,
+      shutdown.clone(),
+    ));
+    info!("Listening on {}/http...", addr);
+  }
+
+  readiness.listening(probe_addr);
+
+  let shipping = {
+    let stats = stats.clone();
+    tokio::spawn(async move { stats.run().await })
+  };
 
   select! {
-    result = server.block_until_done() => {
+    result = server.block_until_done(), if server_listeners => {
       result?;
       info!("Server closed, quitting...");
     },
     _ = shutdown_signal() => {
-      info!("Termination signal received, quitting...");
+      info!("Termination signal received, shutting down...");
     }
   }
 
+  // listeners stop accepting, requests in flight and the buffered stats get
+  // until the deadline to complete
+  let deadline = Instant::now() + Duration::from_secs(args.shutdown_timeout);
+  shutdown.trigger();
+
+  let drained = timeout_at(deadline, async {
+    if server_listeners {
+      if let Err(err) = server.shutdown_gracefully().await {
+        warn!("Unable to shut down listeners: {}", err);
+      }
+    }
+    shutdown.drained().await;
+  });
+  if drained.await.is_err() {
+    warn!(
+      "Shutdown timed out, abandoning {} requests in flight",
+      shutdown.in_flight()
+    );
+  }
+
+  // a batch being shipped is completed, or spooled, before the rest of the
+  // buffer follows
+  let flushed = timeout_at(deadline, async {
+    if let Err(err) = shipping.await {
+      error!("Stats task failed: {}", err);
+    }
+    stats.flush_all().await;
+  });
+  if flushed.await.is_err() {
+    warn!("Shutdown timed out, abandoning buffered stats");
+  }
+
+  telemetry::shutdown();
+
   Ok(())
 }
 
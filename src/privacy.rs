@@ -0,0 +1,123 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Formatter};
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use trust_dns_server::proto::rr::LowerName;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum ClientPrivacy {
+  /// Record the full client address.
+  Full,
+  /// Record only the network of the client (see the prefix options).
+  Truncate,
+  /// Record a keyed hash of the client address, the key rotates periodically.
+  Hash,
+  /// Don't record the client at all.
+  Drop,
+}
+
+/// Client identity as recorded by stats and logs.
+#[derive(Clone)]
+pub(crate) enum Client {
+  Ip(IpAddr),
+  Hashed(u64),
+  Anonymous,
+}
+
+impl Display for Client {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Client::Ip(ip) => write!(f, "{}", ip),
+      Client::Hashed(hash) => write!(f, "{:016x}", hash),
+      Client::Anonymous => Ok(()),
+    }
+  }
+}
+
+pub(crate) struct PrivacyConfig {
+  pub(crate) client: ClientPrivacy,
+  pub(crate) ipv4_prefix: u8,
+  pub(crate) ipv6_prefix: u8,
+  pub(crate) salt_rotation: Duration,
+  pub(crate) only_blocked: bool,
+  pub(crate) omit_query: bool,
+}
+
+/// Anonymizes query events before they reach any stats or log sink.
+pub(crate) struct Privacy {
+  config: PrivacyConfig,
+  salt: RwLock<(RandomState, Instant)>,
+}
+
+impl Privacy {
+  pub(crate) fn new(config: PrivacyConfig) -> Self {
+    Self {
+      config,
+      salt: RwLock::new((RandomState::new(), Instant::now())),
+    }
+  }
+
+  /// Whether a query should be recorded at all.
+  pub(crate) fn record(&self, blocked: bool) -> bool {
+    blocked || !self.config.only_blocked
+  }
+
+  /// Whether recorded clients can be traced back to a single address.
+  pub(crate) fn identifies_clients(&self) -> bool {
+    matches!(self.config.client, ClientPrivacy::Full)
+  }
+
+  pub(crate) fn client(&self, ip: IpAddr) -> Client {
+    match self.config.client {
+      ClientPrivacy::Full => Client::Ip(ip),
+      ClientPrivacy::Truncate => Client::Ip(self.truncate(ip)),
+      ClientPrivacy::Hash => Client::Hashed(self.hash(ip)),
+      ClientPrivacy::Drop => Client::Anonymous,
+    }
+  }
+
+  pub(crate) fn query(&self, name: &LowerName) -> Option<LowerName> {
+    if self.config.omit_query {
+      None
+    } else {
+      Some(name.clone())
+    }
+  }
+
+  fn truncate(&self, ip: IpAddr) -> IpAddr {
+    match ip {
+      IpAddr::V4(ip) => {
+        let mask = u32::MAX
+          .checked_shl(32 - u32::from(self.config.ipv4_prefix.min(32)))
+          .unwrap_or(0);
+        IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+      }
+      IpAddr::V6(ip) => {
+        let mask = u128::MAX
+          .checked_shl(128 - u32::from(self.config.ipv6_prefix.min(128)))
+          .unwrap_or(0);
+        IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+      }
+    }
+  }
+
+  fn hash(&self, ip: IpAddr) -> u64 {
+    {
+      let salt = self.salt.read().unwrap();
+      if salt.1.elapsed() < self.config.salt_rotation {
+        return salt.0.hash_one(ip);
+      }
+    }
+
+    let mut salt = self.salt.write().unwrap();
+    // another thread may have rotated the salt in the meantime
+    if salt.1.elapsed() >= self.config.salt_rotation {
+      *salt = (RandomState::new(), Instant::now());
+    }
+    salt.0.hash_one(ip)
+  }
+}
@@ -0,0 +1,219 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+use trust_dns_server::authority::MessageRequest;
+use trust_dns_server::proto::serialize::binary::BinDecodable;
+use trust_dns_server::server::{Protocol, Request, RequestHandler};
+
+use crate::capture::Capture;
+use crate::proxy::{self, Header, TrustedProxies};
+use crate::shutdown::Shutdown;
+
+/// How long a connection may stay idle, as for the other TCP listeners.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Response size for UDP clients without EDNS (RFC 1035 2.3.4).
+const MIN_UDP_SIZE: u16 = 512;
+
+/// DNS listeners for load balancers in front of rdns. Trusted proxies
+/// prefix connections and datagrams with a PROXY protocol header carrying
+/// the address of the client, other clients are served directly.
+pub(crate) struct ProxiedServer<T> {
+  handler: Arc<T>,
+  proxies: TrustedProxies,
+  shutdown: Shutdown,
+}
+
+impl<T> Clone for ProxiedServer<T> {
+  fn clone(&self) -> Self {
+    Self {
+      handler: self.handler.clone(),
+      proxies: self.proxies.clone(),
+      shutdown: self.shutdown.clone(),
+    }
+  }
+}
+
+impl<T: RequestHandler> ProxiedServer<T> {
+  pub(crate) fn new(handler: Arc<T>, proxies: TrustedProxies, shutdown: Shutdown) -> Self {
+    Self {
+      handler,
+      proxies,
+      shutdown,
+    }
+  }
+
+  /// Answers datagrams, responses are sent back to the proxy without header.
+  pub(crate) async fn serve_udp(self, socket: UdpSocket) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0; usize::from(u16::MAX)];
+
+    loop {
+      let received = select! {
+        received = socket.recv_from(&mut buf) => received,
+        _ = self.shutdown.triggered() => return,
+      };
+
+      let (len, peer) = match received {
+        Ok(received) => received,
+        Err(err) => {
+          debug!("Unable to receive datagram: {}", err);
+          continue;
+        }
+      };
+
+      let datagram = buf[..len].to_vec();
+      let server = self.clone();
+      let socket = socket.clone();
+
+      tokio::spawn(async move {
+        let result = match server.datagram(&datagram, peer).await {
+          Ok(response) => socket.send_to(&response, peer).await.map_err(Into::into),
+          Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+          debug!("Unable to answer datagram of {}: {}", peer, err);
+        }
+      });
+    }
+  }
+
+  /// Answers queries on TCP connections, or TLS connections if a config is
+  /// given. The PROXY protocol header precedes the TLS handshake.
+  pub(crate) async fn serve_tcp(
+    self,
+    listener: TcpListener,
+    tls_config: Option<Arc<ServerConfig>>,
+  ) {
+    let acceptor = tls_config.map(TlsAcceptor::from);
+
+    loop {
+      let accepted = select! {
+        accepted = listener.accept() => accepted,
+        _ = self.shutdown.triggered() => return,
+      };
+
+      let (stream, peer) = match accepted {
+        Ok(accepted) => accepted,
+        Err(err) => {
+          debug!("Unable to accept connection: {}", err);
+          continue;
+        }
+      };
+
+      let server = self.clone();
+      let acceptor = acceptor.clone();
+
+      tokio::spawn(async move {
+        if let Err(err) = server.connection(stream, peer, acceptor).await {
+          debug!("Connection to {} failed: {}", peer, err);
+        }
+      });
+    }
+  }
+
+  async fn datagram(&self, datagram: &[u8], peer: SocketAddr) -> anyhow::Result<Vec<u8>> {
+    let (src, message) = if self.proxies.contains(peer.ip()) {
+      match proxy::parse(datagram)? {
+        Header::Complete { src, len } => (src.unwrap_or(peer), &datagram[len..]),
+        Header::Incomplete(_) => return Err(anyhow!("Incomplete PROXY protocol header")),
+      }
+    } else {
+      (peer, datagram)
+    };
+
+    self.handle(src, Protocol::Udp, message).await
+  }
+
+  async fn connection(
+    &self,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+  ) -> anyhow::Result<()> {
+    let mut src = peer;
+    if self.proxies.contains(peer.ip()) {
+      src = timeout(IDLE_TIMEOUT, proxy::read_header(&mut stream))
+        .await??
+        .unwrap_or(peer);
+    }
+
+    match acceptor {
+      Some(acceptor) => {
+        let stream = timeout(IDLE_TIMEOUT, acceptor.accept(stream)).await??;
+        self.messages(stream, src, Protocol::Tls).await
+      }
+      None => self.messages(stream, src, Protocol::Tcp).await,
+    }
+  }
+
+  /// Answers length prefixed messages until the client closes the
+  /// connection, it idles or shutdown is triggered.
+  async fn messages<S: AsyncRead + AsyncWrite + Unpin>(
+    &self,
+    mut stream: S,
+    src: SocketAddr,
+    protocol: Protocol,
+  ) -> anyhow::Result<()> {
+    loop {
+      let mut len = [0; 2];
+      let read = select! {
+        read = timeout(IDLE_TIMEOUT, stream.read_exact(&mut len)) => read,
+        // the connection is closed between two queries
+        _ = self.shutdown.triggered() => return Ok(()),
+      };
+
+      match read {
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Ok(Err(err)) => return Err(err.into()),
+        Err(_) => return Ok(()),
+      }
+
+      let mut message = vec![0; usize::from(u16::from_be_bytes(len))];
+      timeout(IDLE_TIMEOUT, stream.read_exact(&mut message)).await??;
+
+      let response = self.handle(src, protocol, &message).await?;
+      stream
+        .write_all(&u16::try_from(response.len())?.to_be_bytes())
+        .await?;
+      stream.write_all(&response).await?;
+      stream.flush().await?;
+    }
+  }
+
+  async fn handle(
+    &self,
+    src: SocketAddr,
+    protocol: Protocol,
+    message: &[u8],
+  ) -> anyhow::Result<Vec<u8>> {
+    let message = MessageRequest::from_bytes(message)?;
+
+    // UDP responses are truncated to what the client accepts
+    let max_size = match protocol {
+      Protocol::Udp => message
+        .edns()
+        .map_or(MIN_UDP_SIZE, |edns| edns.max_payload().max(MIN_UDP_SIZE)),
+      _ => u16::MAX,
+    };
+
+    let request = Request::new(message, src, protocol);
+    let capture = Capture::with_max_size(max_size);
+    self.handler.handle_request(&request, capture.clone()).await;
+
+    capture
+      .take()
+      .ok_or_else(|| anyhow!("Handler did not send a response"))
+  }
+}
@@ -0,0 +1,375 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::http::header::FORWARDED;
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+/// Longest v1 header, including the line break.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// Proxies whose claims about the address of the client are believed.
+#[derive(Clone)]
+pub(crate) struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+  pub(crate) fn new(networks: Vec<IpNet>) -> Self {
+    Self(Arc::new(networks))
+  }
+
+  pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+    self.0.iter().any(|network| network.contains(&ip))
+  }
+}
+
+/// Progress of parsing a PROXY protocol header.
+pub(crate) enum Header {
+  /// At least this many more bytes are needed.
+  Incomplete(usize),
+  /// The header is `len` bytes long. The source is `None` for connections
+  /// of the proxy itself, e.g. health checks.
+  Complete { src: Option<SocketAddr>, len: usize },
+}
+
+/// Parses a PROXY protocol v1 or v2 header at the start of the buffer.
+pub(crate) fn parse(buf: &[u8]) -> anyhow::Result<Header> {
+  if buf.starts_with(V1_PREFIX) {
+    parse_v1(buf)
+  } else if buf.starts_with(V2_SIGNATURE) {
+    parse_v2(buf)
+  } else if V1_PREFIX.starts_with(buf) {
+    Ok(Header::Incomplete(V1_PREFIX.len() - buf.len()))
+  } else if V2_SIGNATURE.starts_with(buf) {
+    Ok(Header::Incomplete(V2_HEADER_LEN - buf.len()))
+  } else {
+    Err(anyhow!("Missing PROXY protocol header"))
+  }
+}
+
+/// Reads exactly the PROXY protocol header off the stream, leaving the
+/// data following it untouched.
+pub(crate) async fn read_header<S: AsyncRead + Unpin>(
+  stream: &mut S,
+) -> anyhow::Result<Option<SocketAddr>> {
+  let mut buf = Vec::with_capacity(V2_HEADER_LEN);
+
+  loop {
+    match parse(&buf)? {
+      Header::Complete { src, .. } => return Ok(src),
+      Header::Incomplete(needed) => {
+        let start = buf.len();
+        buf.resize(start + needed, 0);
+        stream.read_exact(&mut buf[start..]).await?;
+      }
+    }
+  }
+}
+
+/// Human readable header, e.g. `PROXY TCP4 192.0.2.1 192.0.2.2 56324 53\r\n`.
+fn parse_v1(buf: &[u8]) -> anyhow::Result<Header> {
+  let Some(end) = buf.windows(2).position(|window| window == b"\r\n") else {
+    return if buf.len() < V1_MAX_LEN {
+      Ok(Header::Incomplete(1))
+    } else {
+      Err(anyhow!("PROXY protocol header too long"))
+    };
+  };
+
+  let line = std::str::from_utf8(&buf[..end])?;
+  let mut fields = line.split(' ').skip(1);
+
+  let src = match fields.next() {
+    Some("TCP4" | "TCP6") => {
+      let (Some(src), Some(_dst), Some(src_port)) = (fields.next(), fields.next(), fields.next())
+      else {
+        return Err(anyhow!("Invalid PROXY protocol header {}", line));
+      };
+      Some(SocketAddr::new(src.parse()?, src_port.parse()?))
+    }
+    Some("UNKNOWN") => None,
+    _ => return Err(anyhow!("Invalid PROXY protocol header {}", line)),
+  };
+
+  Ok(Header::Complete { src, len: end + 2 })
+}
+
+/// Binary header, a signature, version and command, address family and
+/// the length of the addresses following.
+fn parse_v2(buf: &[u8]) -> anyhow::Result<Header> {
+  if buf.len() < V2_HEADER_LEN {
+    return Ok(Header::Incomplete(V2_HEADER_LEN - buf.len()));
+  }
+
+  let version_command = buf[12];
+  if version_command >> 4 != 2 {
+    return Err(anyhow!("Unsupported PROXY protocol version"));
+  }
+
+  let len = V2_HEADER_LEN + usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+  if buf.len() < len {
+    return Ok(Header::Incomplete(len - buf.len()));
+  }
+
+  let addresses = &buf[V2_HEADER_LEN..len];
+  let src = match (version_command & 0x0f, buf[13] >> 4) {
+    // LOCAL
+    (0x0, _) => None,
+    // PROXY over IPv4, source and destination address, then ports
+    (0x1, 0x1) if addresses.len() >= 12 => {
+      let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?);
+      Some(SocketAddr::new(
+        IpAddr::V4(ip),
+        u16::from_be_bytes([addresses[8], addresses[9]]),
+      ))
+    }
+    // PROXY over IPv6
+    (0x1, 0x2) if addresses.len() >= 36 => {
+      let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?);
+      Some(SocketAddr::new(
+        IpAddr::V6(ip),
+        u16::from_be_bytes([addresses[32], addresses[33]]),
+      ))
+    }
+    // unix sockets and unspecified families carry no usable address
+    (0x1, _) => None,
+    _ => return Err(anyhow!("Unsupported PROXY protocol command")),
+  };
+
+  Ok(Header::Complete { src, len })
+}
+
+/// Client address from `Forwarded` or, if missing, `X-Forwarded-For`
+/// headers set by a trusted proxy. Proxies append to the headers, so the
+/// client is the last address that is not a trusted proxy itself. Hops
+/// before an unknown or obfuscated one can't be verified, so the chain
+/// ends there.
+pub(crate) fn forwarded_client(headers: &HeaderMap, proxies: &TrustedProxies) -> Option<IpAddr> {
+  let mut chain: Vec<Option<IpAddr>> = headers
+    .get_all(FORWARDED)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .map(forwarded_for)
+    .collect();
+
+  if chain.is_empty() {
+    chain = headers
+      .get_all("x-forwarded-for")
+      .iter()
+      .filter_map(|value| value.to_str().ok())
+      .flat_map(|value| value.split(','))
+      .map(|ip| ip.trim().parse().ok())
+      .collect();
+  }
+
+  for hop in chain.iter().rev() {
+    match hop {
+      Some(ip) if proxies.contains(*ip) => continue,
+      Some(ip) => return Some(*ip),
+      None => return None,
+    }
+  }
+
+  // every hop is a trusted proxy
+  chain.first().copied().flatten()
+}
+
+/// Address of the `for` parameter of a `Forwarded` element, `None` if it
+/// is missing, `unknown` or obfuscated.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+  element
+    .split(';')
+    .find_map(|pair| {
+      let (key, value) = pair.trim().split_once('=')?;
+      key.eq_ignore_ascii_case("for").then_some(value)
+    })
+    .and_then(forwarded_ip)
+}
+
+/// Address of a `for` parameter, e.g. `192.0.2.60` or `"[2001:db8::17]:4711"`.
+fn forwarded_ip(value: &str) -> Option<IpAddr> {
+  let value = value.trim().trim_matches('"');
+
+  match value.parse::<SocketAddr>() {
+    Ok(addr) => Some(addr.ip()),
+    Err(_) => value
+      .trim_start_matches('[')
+      .trim_end_matches(']')
+      .parse()
+      .ok(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use axum::http::HeaderValue;
+
+  use super::*;
+
+  fn proxies() -> TrustedProxies {
+    TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()])
+  }
+
+  fn header_map(name: &'static str, values: &[&'static str]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for value in values {
+      headers.append(name, HeaderValue::from_static(value));
+    }
+    headers
+  }
+
+  fn ip(ip: &str) -> Option<IpAddr> {
+    Some(ip.parse().unwrap())
+  }
+
+  #[test]
+  fn forwarded_client_is_last_untrusted_hop() {
+    let headers = header_map(
+      "forwarded",
+      &[
+        "for=192.0.2.1, for=198.51.100.7;proto=https",
+        "for=10.0.0.2",
+      ],
+    );
+    assert_eq!(forwarded_client(&headers, &proxies()), ip("198.51.100.7"));
+  }
+
+  #[test]
+  fn forwarded_client_parses_quoted_addresses() {
+    let headers = header_map("forwarded", &["for=\"[2001:db8::17]:4711\""]);
+    assert_eq!(forwarded_client(&headers, &proxies()), ip("2001:db8::17"));
+
+    let headers = header_map("forwarded", &["For=\"192.0.2.60:8080\""]);
+    assert_eq!(forwarded_client(&headers, &proxies()), ip("192.0.2.60"));
+  }
+
+  #[test]
+  fn forwarded_client_ends_at_unknown_hop() {
+    // the client could have written anything before the unknown hop
+    let headers = header_map("forwarded", &["for=192.0.2.1, for=unknown, for=10.0.0.2"]);
+    assert_eq!(forwarded_client(&headers, &proxies()), None);
+
+    let headers = header_map("forwarded", &["for=192.0.2.1, for=_hidden"]);
+    assert_eq!(forwarded_client(&headers, &proxies()), None);
+
+    let headers = header_map("x-forwarded-for", &["192.0.2.1, garbage, 10.0.0.2"]);
+    assert_eq!(forwarded_client(&headers, &proxies()), None);
+  }
+
+  #[test]
+  fn forwarded_client_of_trusted_chain_is_first_hop() {
+    let headers = header_map("x-forwarded-for", &["10.0.0.1, 10.0.0.2"]);
+    assert_eq!(forwarded_client(&headers, &proxies()), ip("10.0.0.1"));
+  }
+
+  #[test]
+  fn forwarded_takes_precedence() {
+    let mut headers = header_map("x-forwarded-for", &["192.0.2.1"]);
+    headers.append(FORWARDED, HeaderValue::from_static("for=198.51.100.7"));
+    assert_eq!(forwarded_client(&headers, &proxies()), ip("198.51.100.7"));
+  }
+
+  #[test]
+  fn forwarded_client_without_headers() {
+    assert_eq!(forwarded_client(&HeaderMap::new(), &proxies()), None);
+  }
+
+  fn complete(header: anyhow::Result<Header>) -> (Option<SocketAddr>, usize) {
+    match header.unwrap() {
+      Header::Complete { src, len } => (src, len),
+      Header::Incomplete(needed) => panic!("incomplete, {} more bytes needed", needed),
+    }
+  }
+
+  #[test]
+  fn parses_v1() {
+    let line = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 53\r\nquery";
+    assert_eq!(
+      complete(parse(line)),
+      (Some("192.0.2.1:56324".parse().unwrap()), line.len() - 5)
+    );
+
+    let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 53\r\n";
+    assert_eq!(
+      complete(parse(line)),
+      (Some("[2001:db8::1]:56324".parse().unwrap()), line.len())
+    );
+
+    let line = b"PROXY UNKNOWN\r\n";
+    assert_eq!(complete(parse(line)), (None, line.len()));
+  }
+
+  #[test]
+  fn parses_v1_incrementally() {
+    assert!(matches!(parse(b"PRO"), Ok(Header::Incomplete(2))));
+    assert!(matches!(
+      parse(b"PROXY TCP4 192.0.2.1"),
+      Ok(Header::Incomplete(1))
+    ));
+  }
+
+  #[test]
+  fn rejects_invalid_v1() {
+    assert!(parse(b"PROXY TCP4 192.0.2.1\r\n").is_err());
+    assert!(parse(b"PROXY UDP4 192.0.2.1 192.0.2.2 56324 53\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 192.0.2.300 192.0.2.2 56324 53\r\n").is_err());
+    assert!(parse(&[b'P', b'R', b'O', b'X', b'Y', b' '].repeat(20)).is_err());
+    assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+  }
+
+  fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(0x20 | command);
+    header.push(family << 4 | 0x1);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(addresses);
+    header
+  }
+
+  #[test]
+  fn parses_v2() {
+    let ipv4 = [192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0, 53];
+    let header = v2(0x1, 0x1, &ipv4);
+    assert_eq!(
+      complete(parse(&header)),
+      (Some("192.0.2.1:56324".parse().unwrap()), 28)
+    );
+
+    let mut ipv6 = Vec::new();
+    ipv6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+    ipv6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+    ipv6.extend_from_slice(&[0xdc, 0x04, 0, 53]);
+    let header = v2(0x1, 0x2, &ipv6);
+    assert_eq!(
+      complete(parse(&header)),
+      (Some("[2001:db8::1]:56324".parse().unwrap()), 52)
+    );
+
+    // LOCAL, e.g. health checks of the proxy
+    assert_eq!(complete(parse(&v2(0x0, 0x0, &[]))), (None, 16));
+    // unix sockets
+    assert_eq!(complete(parse(&v2(0x1, 0x3, &[0; 216]))), (None, 232));
+  }
+
+  #[test]
+  fn parses_v2_incrementally() {
+    let header = v2(0x1, 0x1, &[192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0, 53]);
+    assert!(matches!(parse(&header[..4]), Ok(Header::Incomplete(12))));
+    assert!(matches!(parse(&header[..16]), Ok(Header::Incomplete(12))));
+    assert!(matches!(parse(&header[..20]), Ok(Header::Incomplete(8))));
+  }
+
+  #[test]
+  fn rejects_invalid_v2() {
+    let mut header = v2(0x1, 0x1, &[0; 12]);
+    header[12] = 0x11;
+    assert!(parse(&header).is_err());
+
+    assert!(parse(&v2(0x2, 0x1, &[0; 12])).is_err());
+  }
+}
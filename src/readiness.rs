@@ -0,0 +1,223 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tracing::warn;
+use trust_dns_server::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_server::proto::rr::{LowerName, Name, RecordType};
+use trust_dns_server::proto::serialize::binary::{BinDecodable, BinEncodable};
+
+use crate::blacklist::Blacklist;
+use crate::reload::Swap;
+use crate::zones::Zones;
+
+/// Timeout of the NetBox check and the DNS probe.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a report is served before checking again, so frequent polling
+/// neither floods NetBox nor the listeners.
+const REPORT_TTL: Duration = Duration::from_secs(5);
+
+/// Whether queries can be answered as configured: listeners bound,
+/// blacklist loaded, every forwarding zone with an upstream in rotation,
+/// NetBox reachable and, if configured, a query answered end to end.
+pub(crate) struct Readiness {
+  zones: Swap<Zones>,
+  blacklist: Swap<Blacklist>,
+  probe: Option<LowerName>,
+  /// Set once all listeners are bound, with the address the probe queries.
+  listening: OnceLock<Option<SocketAddr>>,
+  /// Local addresses of the probes waiting for an answer.
+  probing: Mutex<Vec<SocketAddr>>,
+  /// Last report and when it was made, also held while checking.
+  report: tokio::sync::Mutex<Option<(Instant, Report)>>,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct Report {
+  ready: bool,
+  listening: bool,
+  blocklist_loaded: bool,
+  /// Forwarding zones without any upstream in rotation.
+  unhealthy_zones: Vec<String>,
+  /// Whether NetBox is reachable, if configured.
+  netbox: Option<bool>,
+  /// Whether the probe was answered, if configured.
+  probe: Option<bool>,
+}
+
+impl Readiness {
+  pub(crate) fn new(zones: Swap<Zones>, blacklist: Swap<Blacklist>, probe: Option<Name>) -> Self {
+    Self {
+      zones,
+      blacklist,
+      probe: probe.map(LowerName::from),
+      listening: OnceLock::new(),
+      probing: Mutex::new(Vec::new()),
+      report: tokio::sync::Mutex::new(None),
+    }
+  }
+
+  /// Whether the query is one of the probes, which are kept out of the stats.
+  pub(crate) fn is_probe(&self, src: SocketAddr, name: &LowerName) -> bool {
+    match &self.probe {
+      Some(probe) => probe == name && self.probing.lock().unwrap().contains(&src),
+      None => false,
+    }
+  }
+
+  /// Marks the listeners bound, the probe queries the given UDP listener.
+  pub(crate) fn listening(&self, probe_addr: Option<SocketAddr>) {
+    let _ = self.listening.set(probe_addr);
+  }
+
+  /// Runs the checks unless the last report is recent enough.
+  pub(crate) async fn check(&self) -> Report {
+    let mut last = self.report.lock().await;
+    if let Some((checked, report)) = &*last {
+      if checked.elapsed() < REPORT_TTL {
+        return report.clone();
+      }
+    }
+
+    let report = self.run_checks().await;
+    *last = Some((Instant::now(), report.clone()));
+    report
+  }
+
+  async fn run_checks(&self) -> Report {
+    let zones = self.zones.load();
+
+    let unhealthy_zones = zones
+      .upstreams()
+      .iter()
+      .filter(|group| !group.is_healthy())
+      .map(|group| group.zone().to_string())
+      .collect::<Vec<_>>();
+
+    let netbox = match zones.netbox() {
+      Some(client) => Some(match timeout(CHECK_TIMEOUT, client.ping()).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+          warn!("NetBox is unavailable: {}", err);
+          false
+        }
+        Err(_) => {
+          warn!("NetBox is unavailable: timed out");
+          false
+        }
+      }),
+      None => None,
+    };
+
+    let listening = self.listening.get();
+    let probe = match (&self.probe, listening) {
+      (Some(name), Some(Some(addr))) => {
+        Some(match probe(&Name::from(name), *addr, &self.probing).await {
+          Ok(()) => true,
+          Err(err) => {
+            warn!("Readiness probe of {} failed: {}", name, err);
+            false
+          }
+        })
+      }
+      // not answered as long as nothing listens
+      (Some(_), _) => Some(false),
+      (None, _) => None,
+    };
+
+    let blocklist_loaded = self.blacklist.load().is_loaded();
+
+    Report {
+      ready: listening.is_some()
+        && blocklist_loaded
+        && unhealthy_zones.is_empty()
+        && netbox != Some(false)
+        && probe != Some(false),
+      listening: listening.is_some(),
+      blocklist_loaded,
+      unhealthy_zones,
+      netbox,
+      probe,
+    }
+  }
+}
+
+impl Report {
+  pub(crate) fn is_ready(&self) -> bool {
+    self.ready
+  }
+}
+
+/// Queries the A records of the name from the listener, any answer but a
+/// server failure or refusal counts.
+async fn probe(
+  name: &Name,
+  addr: SocketAddr,
+  probing: &Mutex<Vec<SocketAddr>>,
+) -> anyhow::Result<()> {
+  // the listener may be bound to the unspecified address
+  let target = match addr {
+    SocketAddr::V4(addr) if addr.ip().is_unspecified() => {
+      SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+    }
+    SocketAddr::V6(addr) if addr.ip().is_unspecified() => {
+      SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port())
+    }
+    addr => addr,
+  };
+  // bound to the address the query is sent from, so its local address is
+  // the source the listener sees
+  let local = SocketAddr::new(target.ip(), 0);
+
+  let mut query = Message::new();
+  query
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .set_recursion_desired(true)
+    .add_query(Query::query(name.clone(), RecordType::A));
+
+  // a socket of its own, so only the response to the probe is received
+  // and the query is told apart from others by its source
+  let socket = UdpSocket::bind(local).await?;
+  let _probing = Probing::start(probing, socket.local_addr()?);
+  socket.connect(target).await?;
+  socket.send(&query.to_bytes()?).await?;
+
+  let mut buf = [0; 4096];
+  let len = timeout(CHECK_TIMEOUT, socket.recv(&mut buf)).await??;
+  let response = Message::from_bytes(&buf[..len])?;
+
+  match response.response_code() {
+    ResponseCode::ServFail | ResponseCode::Refused => {
+      Err(anyhow!("answered {}", response.response_code()))
+    }
+    _ => Ok(()),
+  }
+}
+
+/// Local address of a probe in flight, registered until dropped.
+struct Probing<'a> {
+  probing: &'a Mutex<Vec<SocketAddr>>,
+  addr: SocketAddr,
+}
+
+impl<'a> Probing<'a> {
+  fn start(probing: &'a Mutex<Vec<SocketAddr>>, addr: SocketAddr) -> Self {
+    probing.lock().unwrap().push(addr);
+    Self { probing, addr }
+  }
+}
+
+impl Drop for Probing<'_> {
+  fn drop(&mut self) {
+    let mut probing = self.probing.lock().unwrap();
+    if let Some(index) = probing.iter().position(|addr| *addr == self.addr) {
+      probing.swap_remove(index);
+    }
+  }
+}
@@ -0,0 +1,313 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use clap::ArgMatches;
+use tokio::sync::Mutex;
+use toml_edit::Item;
+use tracing::{error, info};
+use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+use url::Url;
+
+use crate::args::{self, Args};
+use crate::blacklist::Blacklist;
+use crate::client_groups::ClientGroups;
+use crate::config::{self, Edits};
+use crate::stats::influx::Influx;
+use crate::stats::spool::Spool;
+use crate::stats::Sink;
+use crate::zones::Zones;
+
+/// Shared value replaced as a whole on reload. Readers keep the value they
+/// loaded, so in-flight queries finish with the previous configuration.
+pub(crate) struct Swap<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Swap<T> {
+  pub(crate) fn new(value: T) -> Self {
+    Self(Arc::new(RwLock::new(Arc::new(value))))
+  }
+
+  pub(crate) fn load(&self) -> Arc<T> {
+    self.0.read().unwrap().clone()
+  }
+
+  pub(crate) fn store(&self, value: T) {
+    let previous = std::mem::replace(&mut *self.0.write().unwrap(), Arc::new(value));
+    // a large previous value is freed outside of the lock
+    drop(previous);
+  }
+}
+
+// derive(Clone) would require T: Clone
+impl<T> Clone for Swap<T> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+#[async_trait]
+impl<T: RequestHandler> RequestHandler for Swap<T> {
+  async fn handle_request<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+  ) -> ResponseInfo {
+    self.load().handle_request(request, response_handle).await
+  }
+}
+
+/// Rebuilds zones, blacklist and stats sink from the arguments and the
+/// config file read again, with the edits of the admin API on top. Nothing
+/// is replaced unless all of them could be built, otherwise the previous
+/// configuration stays in place.
+///
+/// Listeners, cache, buffer and privacy settings require a restart.
+pub(crate) struct Reloader {
+  matches: ArgMatches,
+  zones: Swap<Zones>,
+  blacklist: Swap<Blacklist>,
+  client_groups: Swap<ClientGroups>,
+  sink: Swap<Option<Sink>>,
+  /// The lock serializes reloads and edits.
+  state: Mutex<State>,
+}
+
+/// Everything replaced on reload.
+struct Built {
+  zones: Zones,
+  blacklist: Blacklist,
+  sources: Vec<Url>,
+  client_groups: ClientGroups,
+  sink: Option<Sink>,
+}
+
+struct State {
+  /// Sources of the current blacklist, which is only fetched again if they
+  /// change.
+  sources: Vec<Url>,
+  /// Settings changed through the admin API and not persisted, which
+  /// survive reloads but not restarts.
+  edits: Edits,
+}
+
+impl Reloader {
+  pub(crate) fn new(
+    matches: ArgMatches,
+    zones: Swap<Zones>,
+    blacklist: Swap<Blacklist>,
+    sources: Vec<Url>,
+    client_groups: Swap<ClientGroups>,
+    sink: Swap<Option<Sink>>,
+  ) -> Self {
+    Self {
+      matches,
+      zones,
+      blacklist,
+      client_groups,
+      sink,
+      state: Mutex::new(State {
+        sources,
+        edits: Edits::default(),
+      }),
+    }
+  }
+
+  pub(crate) async fn reload(&self) -> anyhow::Result<()> {
+    info!("Reloading configuration...");
+
+    let mut state = self.state.lock().await;
+    let state = &mut *state;
+    match self.try_reload(&mut state.sources, &state.edits).await {
+      Ok(()) => {
+        info!("Configuration reloaded");
+        Ok(())
+      }
+      Err(err) => {
+        error!(
+          "Unable to reload configuration, keeping the current one: {}",
+          err
+        );
+        Err(err)
+      }
+    }
+  }
+
+  async fn try_reload(&self, sources: &mut Vec<Url>, edits: &Edits) -> anyhow::Result<()> {
+    let built = self.build(sources, edits).await?;
+    self.store(sources, built);
+
+    Ok(())
+  }
+
+  /// Builds everything replaced on reload without putting it in place.
+  async fn build(&self, sources: &[Url], edits: &Edits) -> anyhow::Result<Built> {
+    let args = Args::from_matches(&self.matches, edits)?;
+    config::check(&args)?;
+
+    let zones = Zones::build(&args).await?;
+    let blacklist = if args.blacklist_sources != sources {
+      blacklist(&args.blacklist_sources).await?
+    } else {
+      (*self.blacklist.load()).clone()
+    }
+    .with_exceptions(&args.blacklist_allow, &args.blacklist_deny);
+    let current = self.sink.load();
+    let sink = sink(&args, (*current).as_ref()).await?;
+
+    Ok(Built {
+      zones,
+      blacklist,
+      sources: args.blacklist_sources,
+      client_groups: ClientGroups::new(&args.client_groups, &args.blacklist_bypass),
+      sink,
+    })
+  }
+
+  fn store(&self, sources: &mut Vec<Url>, built: Built) {
+    self.zones.store(built.zones);
+    self.blacklist.store(built.blacklist);
+    *sources = built.sources;
+    self.client_groups.store(built.client_groups);
+    self.sink.store(built.sink);
+  }
+
+  /// Changes the setting at the path of the config file to the result of
+  /// `change`, given the current setting and arguments. The change is
+  /// rejected unless the configuration it results in reloads, and with
+  /// `persist` unless it is saved first. Settings given on the command line
+  /// or as environment variable can't be changed.
+  pub(crate) async fn edit<F>(
+    &self,
+    arg: &str,
+    path: &[&str],
+    persist: bool,
+    change: F,
+  ) -> anyhow::Result<()>
+  where
+    F: FnOnce(Option<Item>, &Args) -> anyhow::Result<Item>,
+  {
+    if args::is_explicit(&self.matches, arg) {
+      return Err(anyhow!(
+        "{} is given as argument and can't be changed",
+        path.join(".")
+      ));
+    }
+    let Some(file) = self.matches.get_one::<PathBuf>("config") else {
+      return Err(anyhow!("Changes require a config file"));
+    };
+
+    let mut state = self.state.lock().await;
+    let state = &mut *state;
+
+    let args = Args::from_matches(&self.matches, &state.edits)?;
+    let item = change(state.edits.current(file, path)?, &args)?;
+
+    let mut edits = state.edits.clone();
+    edits.set(path, item);
+    let built = self.build(&state.sources, &edits).await?;
+    if persist {
+      edits.persist(file, path)?;
+    }
+    self.store(&mut state.sources, built);
+    state.edits = edits;
+
+    if persist {
+      info!(
+        "Changed {} and saved it to {}",
+        path.join("."),
+        file.display()
+      );
+    } else {
+      info!("Changed {} until restart", path.join("."));
+    }
+
+    Ok(())
+  }
+
+  /// Fetches the blacklist of the current sources, unless a reload did in
+  /// the meantime. Nothing is blocked until it completes.
+  pub(crate) async fn load_blacklist(&self) -> anyhow::Result<()> {
+    let state = self.state.lock().await;
+
+    let current = self.blacklist.load();
+    if !current.is_loaded() {
+      let loaded = blacklist(&state.sources)
+        .await?
+        .with_exceptions_of(&current);
+      self.blacklist.store(loaded);
+    }
+
+    Ok(())
+  }
+
+  /// Fetches the lists of the current sources again.
+  pub(crate) async fn refresh_blacklist(&self) -> anyhow::Result<()> {
+    let state = self.state.lock().await;
+
+    let current = self.blacklist.load();
+    let refreshed = blacklist(&state.sources)
+      .await?
+      .with_exceptions_of(&current);
+    self.blacklist.store(refreshed);
+
+    Ok(())
+  }
+
+  /// Reloads on every SIGHUP.
+  #[cfg(unix)]
+  pub(crate) async fn on_hangup(&self) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+      Ok(hangup) => hangup,
+      Err(err) => {
+        error!("Unable to install SIGHUP handler: {}", err);
+        return;
+      }
+    };
+
+    while hangup.recv().await.is_some() {
+      // failures are logged by reload
+      let _ = self.reload().await;
+    }
+  }
+}
+
+/// Blacklist fetched from the sources, or the built-in ones if empty.
+pub(crate) async fn blacklist(sources: &[Url]) -> anyhow::Result<Blacklist> {
+  let mut blacklist = if sources.is_empty() {
+    Blacklist::new()
+  } else {
+    Blacklist::with_sources(sources.to_vec())
+  };
+  blacklist.update().await?;
+
+  Ok(blacklist)
+}
+
+/// Stats sink of the arguments, `None` if not configured. The spool of the
+/// current sink is taken over if it lives in the same directory, as a
+/// second spool must not open it while the first one is still in use.
+pub(crate) async fn sink(args: &Args, current: Option<&Sink>) -> anyhow::Result<Option<Sink>> {
+  let Some(config) = args.influx_config() else {
+    return Ok(None);
+  };
+
+  let current_spool = current
+    .and_then(|sink| sink.spool.as_ref())
+    .filter(|spool| Some(spool.dir()) == args.stats_spool_dir.as_deref());
+
+  let spool = match (current_spool, &args.stats_spool_dir) {
+    (Some(spool), _) => Some(spool.clone()),
+    (None, Some(dir)) => Some(Arc::new(
+      Spool::open(dir.clone(), args.stats_spool_max_size).await?,
+    )),
+    (None, None) => None,
+  };
+
+  Ok(Some(Sink {
+    influx: Influx::new(config)?,
+    spool,
+  }))
+}
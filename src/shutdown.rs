@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+/// Graceful shutdown: once triggered, listeners stop accepting and the
+/// requests still in flight are waited for.
+#[derive(Clone)]
+pub(crate) struct Shutdown(Arc<Inner>);
+
+struct Inner {
+  token: CancellationToken,
+  in_flight: AtomicUsize,
+  idle: Notify,
+}
+
+impl Shutdown {
+  pub(crate) fn new() -> Self {
+    Self(Arc::new(Inner {
+      token: CancellationToken::new(),
+      in_flight: AtomicUsize::new(0),
+      idle: Notify::new(),
+    }))
+  }
+
+  pub(crate) fn trigger(&self) {
+    self.0.token.cancel();
+  }
+
+  /// Completes once shutdown is triggered.
+  pub(crate) async fn triggered(&self) {
+    self.0.token.cancelled().await
+  }
+
+  pub(crate) fn is_triggered(&self) -> bool {
+    self.0.token.is_cancelled()
+  }
+
+  /// Completes once no request is in flight.
+  pub(crate) async fn drained(&self) {
+    loop {
+      // registered before checking, so a request completing in between
+      // isn't missed
+      let idle = self.0.idle.notified();
+      if self.0.in_flight.load(Ordering::Acquire) == 0 {
+        return;
+      }
+      idle.await;
+    }
+  }
+
+  pub(crate) fn in_flight(&self) -> usize {
+    self.0.in_flight.load(Ordering::Acquire)
+  }
+
+  fn start(&self) -> InFlight {
+    self.0.in_flight.fetch_add(1, Ordering::AcqRel);
+    InFlight(self.clone())
+  }
+}
+
+/// A request being answered, counted until dropped.
+struct InFlight(Shutdown);
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    if (self.0).0.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+      (self.0).0.idle.notify_waiters();
+    }
+  }
+}
+
+/// Handler counting the requests in flight, which shutdown waits for.
+pub(crate) struct Tracked<T> {
+  handler: T,
+  shutdown: Shutdown,
+}
+
+impl<T> Tracked<T> {
+  pub(crate) fn new(handler: T, shutdown: Shutdown) -> Self {
+    Self { handler, shutdown }
+  }
+}
+
+#[async_trait]
+impl<T: RequestHandler> RequestHandler for Tracked<T> {
+  async fn handle_request<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+  ) -> ResponseInfo {
+    let _in_flight = self.shutdown.start();
+    self.handler.handle_request(request, response_handle).await
+  }
+}
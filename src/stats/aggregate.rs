@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::privacy::Client;
+use crate::stats::Entry;
+
+const MINUTES: usize = 24 * 60;
+const HOURS: usize = 24;
+/// Upper bound of the shards, which multiply the memory used.
+const MAX_SHARDS: usize = 16;
+
+/// Rolling in-process aggregates of the last 24 hours.
+///
+/// Query counts are kept in per-minute buckets, the top lists in per-hour
+/// slots that are pruned to `top_capacity` names each, so memory stays
+/// bounded no matter how many distinct names or clients show up.
+///
+/// Queries are recorded into one of several shards in turn, so concurrent
+/// queries rarely wait on each other, and the shards are merged on reads.
+pub(crate) struct Aggregates {
+  top_capacity: usize,
+  shards: Vec<Mutex<Shard>>,
+  next: AtomicUsize,
+}
+
+struct Shard {
+  minutes: Vec<Bucket>,
+  hours: Vec<TopSlot>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bucket {
+  minute: u64,
+  queries: u64,
+  blocked: u64,
+  duration_us: u64,
+}
+
+#[derive(Default)]
+struct TopSlot {
+  hour: u64,
+  domains: Counter,
+  blocked: Counter,
+  clients: Counter,
+}
+
+#[derive(Default)]
+struct Counter(HashMap<String, u64>);
+
+pub(crate) struct Summary {
+  pub(crate) queries: u64,
+  pub(crate) blocked: u64,
+  pub(crate) avg_duration_us: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Point {
+  /// Start of the minute in seconds since the unix epoch.
+  timestamp: u64,
+  queries: u64,
+  blocked: u64,
+  avg_duration_us: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TopEntry {
+  name: String,
+  count: u64,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TopList {
+  Domains,
+  Blocked,
+  Clients,
+}
+
+impl Counter {
+  fn add(&mut self, key: String, capacity: usize) {
+    *self.0.entry(key).or_default() += 1;
+
+    // prune in bulk to amortize the cost, the long tail is irrelevant for a top list
+    if self.0.len() > capacity * 2 {
+      let mut entries = self.0.drain().collect::<Vec<_>>();
+      entries.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+      entries.truncate(capacity);
+      self.0.extend(entries);
+    }
+  }
+}
+
+impl Aggregates {
+  pub(crate) fn new(top_capacity: usize) -> Self {
+    let shards = thread::available_parallelism().map_or(1, usize::from);
+
+    Self {
+      top_capacity: top_capacity.max(1),
+      shards: (0..shards.min(MAX_SHARDS))
+        .map(|_| {
+          Mutex::new(Shard {
+            minutes: vec![Bucket::default(); MINUTES],
+            hours: (0..HOURS).map(|_| TopSlot::default()).collect(),
+          })
+        })
+        .collect(),
+      next: AtomicUsize::new(0),
+    }
+  }
+
+  pub(crate) fn record(&self, entry: &Entry) {
+    let minute = epoch_minute(entry.timestamp);
+    let hour = minute / 60;
+
+    let shard = self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len();
+    let mut state = self.shards[shard].lock().unwrap();
+
+    let bucket = &mut state.minutes[minute as usize % MINUTES];
+    if bucket.minute != minute {
+      *bucket = Bucket {
+        minute,
+        ..Bucket::default()
+      };
+    }
+    bucket.queries += 1;
+    bucket.duration_us += entry.duration.as_micros() as u64;
+    if entry.blocked {
+      bucket.blocked += 1;
+    }
+
+    let slot = &mut state.hours[hour as usize % HOURS];
+    if slot.hour != hour {
+      *slot = TopSlot {
+        hour,
+        ..TopSlot::default()
+      };
+    }
+
+    if let Some(query) = &entry.query {
+      let counter = if entry.blocked {
+        &mut slot.blocked
+      } else {
+        &mut slot.domains
+      };
+      counter.add(query.to_string(), self.top_capacity);
+    }
+
+    match (&entry.src, &entry.src_name) {
+      (Client::Anonymous, _) => {}
+      (src, Some(name)) => slot
+        .clients
+        .add(format!("{} ({})", name, src), self.top_capacity),
+      (src, None) => slot.clients.add(src.to_string(), self.top_capacity),
+    }
+  }
+
+  pub(crate) fn summary(&self) -> Summary {
+    let mut total = Bucket::default();
+    for bucket in self.minutes().values() {
+      total.queries += bucket.queries;
+      total.blocked += bucket.blocked;
+      total.duration_us += bucket.duration_us;
+    }
+
+    Summary {
+      queries: total.queries,
+      blocked: total.blocked,
+      avg_duration_us: average(total.duration_us, total.queries),
+    }
+  }
+
+  /// Per-minute points of the last 24 hours, oldest first. Minutes without
+  /// any queries are omitted.
+  pub(crate) fn timeseries(&self) -> Vec<Point> {
+    self
+      .minutes()
+      .into_values()
+      .filter(|bucket| bucket.queries > 0)
+      .map(|bucket| Point {
+        timestamp: bucket.minute * 60,
+        queries: bucket.queries,
+        blocked: bucket.blocked,
+        avg_duration_us: average(bucket.duration_us, bucket.queries),
+      })
+      .collect()
+  }
+
+  /// Buckets of the last 24 hours merged across shards, by minute.
+  fn minutes(&self) -> BTreeMap<u64, Bucket> {
+    let now = epoch_minute(SystemTime::now());
+    let mut merged = BTreeMap::<u64, Bucket>::new();
+
+    for shard in &self.shards {
+      let shard = shard.lock().unwrap();
+      for bucket in shard.minutes.iter().filter(|b| is_recent(b.minute, now)) {
+        let total = merged.entry(bucket.minute).or_insert(Bucket {
+          minute: bucket.minute,
+          ..Bucket::default()
+        });
+        total.queries += bucket.queries;
+        total.blocked += bucket.blocked;
+        total.duration_us += bucket.duration_us;
+      }
+    }
+
+    merged
+  }
+
+  pub(crate) fn top(&self, list: TopList, limit: usize) -> Vec<TopEntry> {
+    let now = epoch_minute(SystemTime::now()) / 60;
+
+    let mut merged = HashMap::<String, u64>::new();
+    for shard in &self.shards {
+      let shard = shard.lock().unwrap();
+      for slot in shard
+        .hours
+        .iter()
+        .filter(|slot| slot.hour <= now && now - slot.hour < HOURS as u64)
+      {
+        let counter = match list {
+          TopList::Domains => &slot.domains,
+          TopList::Blocked => &slot.blocked,
+          TopList::Clients => &slot.clients,
+        };
+
+        for (name, count) in &counter.0 {
+          match merged.get_mut(name) {
+            Some(total) => *total += count,
+            None => {
+              merged.insert(name.clone(), *count);
+            }
+          }
+        }
+      }
+    }
+
+    let mut entries = merged
+      .into_iter()
+      .map(|(name, count)| TopEntry { name, count })
+      .collect::<Vec<_>>();
+
+    entries.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    entries.truncate(limit);
+    entries
+  }
+}
+
+fn epoch_minute(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |duration| duration.as_secs() / 60)
+}
+
+fn is_recent(minute: u64, now: u64) -> bool {
+  minute <= now && now - minute < MINUTES as u64
+}
+
+fn average(total: u64, count: u64) -> u64 {
+  total.checked_div(count).unwrap_or(0)
+}
@@ -0,0 +1,100 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+use crossbeam_queue::ArrayQueue;
+use tokio::sync::Notify;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum OverflowPolicy {
+  /// Evict the oldest buffered event to make room for the new one.
+  DropOldest,
+  /// Discard the new event and keep the buffered ones.
+  DropNewest,
+  /// While the buffer is full, only every n-th new event replaces the oldest one.
+  Sample,
+}
+
+/// Bounded, lock-free event buffer between the request path and the sink.
+///
+/// Pushing never blocks or awaits, so a slow or unavailable sink can only
+/// cost events (counted in `dropped`), never memory or query latency.
+pub(crate) struct Buffer<E> {
+  queue: ArrayQueue<E>,
+  policy: OverflowPolicy,
+  sample_rate: u64,
+  overflowed: AtomicU64,
+  dropped: AtomicU64,
+  notify: Notify,
+  batch_size: usize,
+}
+
+impl<E> Buffer<E> {
+  pub(crate) fn new(
+    capacity: usize,
+    batch_size: usize,
+    policy: OverflowPolicy,
+    sample_rate: u64,
+  ) -> Self {
+    Self {
+      queue: ArrayQueue::new(capacity.max(1)),
+      policy,
+      sample_rate: sample_rate.max(1),
+      overflowed: AtomicU64::new(0),
+      dropped: AtomicU64::new(0),
+      notify: Notify::new(),
+      batch_size: batch_size.max(1),
+    }
+  }
+
+  pub(crate) fn push(&self, entry: E) {
+    let dropped = match self.policy {
+      OverflowPolicy::DropOldest => self.queue.force_push(entry).is_some(),
+      OverflowPolicy::DropNewest => self.queue.push(entry).is_err(),
+      OverflowPolicy::Sample => match self.queue.push(entry) {
+        Ok(()) => false,
+        Err(entry) => {
+          let n = self.overflowed.fetch_add(1, Ordering::Relaxed);
+          if n % self.sample_rate == 0 {
+            self.queue.force_push(entry);
+          }
+          true
+        }
+      },
+    };
+
+    if dropped {
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if self.queue.len() >= self.batch_size {
+      self.notify.notify_one();
+    }
+  }
+
+  /// Removes up to one batch of events in insertion order.
+  pub(crate) fn drain(&self) -> Vec<E> {
+    let mut entries = Vec::with_capacity(self.batch_size.min(self.queue.len()));
+
+    while entries.len() < self.batch_size {
+      match self.queue.pop() {
+        Some(entry) => entries.push(entry),
+        None => break,
+      }
+    }
+
+    entries
+  }
+
+  /// Resolves as soon as a full batch is buffered.
+  pub(crate) async fn batch_ready(&self) {
+    self.notify.notified().await
+  }
+
+  pub(crate) fn len(&self) -> usize {
+    self.queue.len()
+  }
+
+  pub(crate) fn dropped(&self) -> u64 {
+    self.dropped.load(Ordering::Relaxed)
+  }
+}
@@ -0,0 +1,345 @@
+use std::fmt::{Display, Write as _};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING};
+use reqwest::{Client, StatusCode, Url};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum InfluxVersion {
+  /// `/write?db=` with optional basic auth
+  V1,
+  /// `/api/v2/write?org=&bucket=` with token auth
+  V2,
+  /// `/api/v3/write_lp?db=` with bearer token auth
+  V3,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum WritePrecision {
+  #[value(name = "s")]
+  Seconds,
+  #[value(name = "ms")]
+  Milliseconds,
+  #[value(name = "us")]
+  Microseconds,
+  #[value(name = "ns")]
+  Nanoseconds,
+}
+
+impl WritePrecision {
+  pub(crate) fn timestamp(self, time: SystemTime) -> anyhow::Result<u128> {
+    let since_epoch = time.duration_since(UNIX_EPOCH)?;
+
+    Ok(match self {
+      WritePrecision::Seconds => since_epoch.as_secs() as u128,
+      WritePrecision::Milliseconds => since_epoch.as_millis(),
+      WritePrecision::Microseconds => since_epoch.as_micros(),
+      WritePrecision::Nanoseconds => since_epoch.as_nanos(),
+    })
+  }
+
+  fn query_value(self, version: InfluxVersion) -> &'static str {
+    match (version, self) {
+      (InfluxVersion::V3, WritePrecision::Seconds) => "second",
+      (InfluxVersion::V3, WritePrecision::Milliseconds) => "millisecond",
+      (InfluxVersion::V3, WritePrecision::Microseconds) => "microsecond",
+      (InfluxVersion::V3, WritePrecision::Nanoseconds) => "nanosecond",
+      (_, WritePrecision::Seconds) => "s",
+      (_, WritePrecision::Milliseconds) => "ms",
+      (InfluxVersion::V1, WritePrecision::Microseconds) => "u",
+      (_, WritePrecision::Microseconds) => "us",
+      (InfluxVersion::V1, WritePrecision::Nanoseconds) => "n",
+      (_, WritePrecision::Nanoseconds) => "ns",
+    }
+  }
+}
+
+pub(crate) struct InfluxConfig {
+  pub(crate) version: InfluxVersion,
+  pub(crate) url: Url,
+  pub(crate) precision: WritePrecision,
+  pub(crate) token: Option<String>,
+  pub(crate) org: Option<String>,
+  pub(crate) bucket: Option<String>,
+  pub(crate) database: Option<String>,
+  pub(crate) username: Option<String>,
+  pub(crate) password: Option<String>,
+}
+
+enum Auth {
+  None,
+  Header(String),
+  Basic(String, Option<String>),
+}
+
+pub(crate) struct Influx {
+  client: Client,
+  endpoint: Url,
+  auth: Auth,
+  precision: WritePrecision,
+  unsigned: bool,
+}
+
+impl Influx {
+  pub(crate) fn new(config: InfluxConfig) -> anyhow::Result<Self> {
+    fn required(value: Option<String>, name: &str) -> anyhow::Result<String> {
+      value.ok_or_else(|| anyhow!("Missing stats {} for the configured InfluxDB version", name))
+    }
+
+    let precision = config.precision.query_value(config.version);
+
+    let (endpoint, auth) = match config.version {
+      InfluxVersion::V1 => {
+        let mut endpoint = config.url.join("write")?;
+        endpoint
+          .query_pairs_mut()
+          .append_pair("db", &required(config.database, "database")?)
+          .append_pair("precision", precision);
+
+        let auth = match config.username {
+          Some(username) => Auth::Basic(username, config.password),
+          None => Auth::None,
+        };
+
+        (endpoint, auth)
+      }
+      InfluxVersion::V2 => {
+        let mut endpoint = config.url.join("api/v2/write")?;
+        endpoint
+          .query_pairs_mut()
+          .append_pair("org", &required(config.org, "org")?)
+          .append_pair("bucket", &required(config.bucket, "bucket")?)
+          .append_pair("precision", precision);
+
+        let auth = Auth::Header(format!("Token {}", required(config.token, "token")?));
+
+        (endpoint, auth)
+      }
+      InfluxVersion::V3 => {
+        let mut endpoint = config.url.join("api/v3/write_lp")?;
+        endpoint
+          .query_pairs_mut()
+          .append_pair("db", &required(config.database, "database")?)
+          .append_pair("precision", precision);
+
+        let auth = match config.token {
+          Some(token) => Auth::Header(format!("Bearer {}", token)),
+          None => Auth::None,
+        };
+
+        (endpoint, auth)
+      }
+    };
+
+    Ok(Self {
+      client: Client::new(),
+      endpoint,
+      auth,
+      precision: config.precision,
+      unsigned: !matches!(config.version, InfluxVersion::V1),
+    })
+  }
+
+  pub(crate) fn precision(&self) -> WritePrecision {
+    self.precision
+  }
+
+  /// Whether unsigned integer fields are accepted, which InfluxDB 1.x
+  /// doesn't by default.
+  pub(crate) fn supports_unsigned(&self) -> bool {
+    self.unsigned
+  }
+
+  /// Writes a gzip compressed line protocol body.
+  pub(crate) async fn write(&self, body: Vec<u8>) -> anyhow::Result<()> {
+    let request = self
+      .client
+      .post(self.endpoint.clone())
+      .header(CONTENT_ENCODING, "gzip")
+      .body(body);
+
+    let request = match &self.auth {
+      Auth::None => request,
+      Auth::Header(value) => request.header(AUTHORIZATION, value),
+      Auth::Basic(username, password) => request.basic_auth(username, password.as_ref()),
+    };
+
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+      return Err(
+        Rejected {
+          status,
+          message: response.text().await.unwrap_or_default(),
+        }
+        .into(),
+      );
+    }
+    response.error_for_status()?;
+
+    Ok(())
+  }
+}
+
+/// A batch refused by the sink with a client error, which writing it again
+/// won't change.
+#[derive(Debug)]
+pub(crate) struct Rejected {
+  status: StatusCode,
+  message: String,
+}
+
+impl Display for Rejected {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Batch rejected with {}: {}",
+      self.status,
+      self.message.trim()
+    )
+  }
+}
+
+impl std::error::Error for Rejected {}
+
+/// A single point in the InfluxDB line protocol, tags are added first and
+/// turn into [`Fields`] with the first field.
+///
+/// Measurement, tag and field names are escaped as required by the protocol,
+/// so arbitrary query names or addresses can be used as tag values.
+pub(crate) struct Line {
+  buf: String,
+}
+
+/// A point with at least one field, as the protocol requires.
+pub(crate) struct Fields {
+  buf: String,
+}
+
+impl Line {
+  pub(crate) fn new(measurement: &str) -> Self {
+    let mut buf = String::new();
+    escape(&mut buf, measurement, &[',', ' ']);
+    Self { buf }
+  }
+
+  /// Adds a tag, empty values are skipped as the protocol does not allow them.
+  pub(crate) fn tag(mut self, key: &str, value: impl Display) -> Self {
+    let value = value.to_string();
+    if !value.is_empty() {
+      self.buf.push(',');
+      escape(&mut self.buf, key, &[',', '=', ' ']);
+      self.buf.push('=');
+      escape(&mut self.buf, &value, &[',', '=', ' ']);
+    }
+
+    self
+  }
+
+  /// Adds an integer field, which unlike unsigned integers is supported by
+  /// every InfluxDB version.
+  pub(crate) fn field(mut self, key: &str, value: i64) -> Fields {
+    self.buf.push(' ');
+    Fields::push(&mut self.buf, key, value, 'i');
+    Fields { buf: self.buf }
+  }
+
+  /// Adds an unsigned integer field, which InfluxDB 1.x rejects.
+  pub(crate) fn unsigned(mut self, key: &str, value: u64) -> Fields {
+    self.buf.push(' ');
+    Fields::push(&mut self.buf, key, value, 'u');
+    Fields { buf: self.buf }
+  }
+}
+
+impl Fields {
+  pub(crate) fn field(mut self, key: &str, value: i64) -> Self {
+    self.buf.push(',');
+    Self::push(&mut self.buf, key, value, 'i');
+    self
+  }
+
+  pub(crate) fn unsigned(mut self, key: &str, value: u64) -> Self {
+    self.buf.push(',');
+    Self::push(&mut self.buf, key, value, 'u');
+    self
+  }
+
+  fn push(buf: &mut String, key: &str, value: impl Display, suffix: char) {
+    escape(buf, key, &[',', '=', ' ']);
+    // writing into a string can't fail
+    let _ = write!(buf, "={}{}", value, suffix);
+  }
+
+  pub(crate) fn write<W: Write>(self, w: &mut W, timestamp: u128) -> std::io::Result<()> {
+    writeln!(w, "{} {}", self.buf, timestamp)
+  }
+}
+
+fn escape(buf: &mut String, value: &str, special: &[char]) {
+  for c in value.chars() {
+    match c {
+      '\n' => buf.push_str("\\n"),
+      c if c == '\\' || special.contains(&c) => {
+        buf.push('\\');
+        buf.push(c);
+      }
+      c => buf.push(c),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(fields: Fields) -> String {
+    let mut buf = Vec::new();
+    fields.write(&mut buf, 1_700_000_000).unwrap();
+    String::from_utf8(buf).unwrap()
+  }
+
+  #[test]
+  fn encodes_tags_and_fields() {
+    let line = Line::new("queries")
+      .tag("src", "192.0.2.1")
+      .tag("type", "AAAA")
+      .field("count", -3)
+      .unsigned("duration", 12);
+
+    assert_eq!(
+      encode(line),
+      "queries,src=192.0.2.1,type=AAAA count=-3i,duration=12u 1700000000\n"
+    );
+  }
+
+  #[test]
+  fn skips_empty_tags() {
+    let line = Line::new("queries")
+      .tag("src_name", "")
+      .field("duration", 1);
+    assert_eq!(encode(line), "queries duration=1i 1700000000\n");
+  }
+
+  #[test]
+  fn escapes_special_characters() {
+    let line = Line::new("dns queries,v2")
+      .tag("query name", "a=b,c d")
+      .tag("path", "C:\\dns\\")
+      .field("a,b=c", 1);
+
+    assert_eq!(
+      encode(line),
+      "dns\\ queries\\,v2,query\\ name=a\\=b\\,c\\ d,path=C:\\\\dns\\\\ a\\,b\\=c=1i 1700000000\n"
+    );
+  }
+
+  #[test]
+  fn escapes_newlines() {
+    let line = Line::new("queries").tag("query", "a\nb").field("n", 0);
+    assert_eq!(encode(line), "queries,query=a\\nb n=0i 1700000000\n");
+  }
+}
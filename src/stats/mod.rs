@@ -0,0 +1,474 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::select;
+use tracing::{error, field, info_span, warn, Instrument};
+use trust_dns_server::authority::MessageResponseBuilder;
+use trust_dns_server::proto::op::ResponseCode;
+use trust_dns_server::proto::rr::{LowerName, RecordType};
+use trust_dns_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+use crate::blacklist::Blacklist;
+use crate::client_groups::{ClientGroup, ClientGroups};
+use crate::hostnames::Hostnames;
+use crate::privacy::{Client, Privacy};
+use crate::readiness::Readiness;
+use crate::reload::Swap;
+use crate::shutdown::Shutdown;
+use crate::stats::aggregate::Aggregates;
+use crate::stats::buffer::{Buffer, OverflowPolicy};
+use crate::stats::influx::{Influx, Line, Rejected};
+use crate::stats::query_log::QueryLog;
+use crate::stats::spool::Spool;
+
+pub(crate) mod aggregate;
+pub(crate) mod buffer;
+pub(crate) mod influx;
+pub(crate) mod query_log;
+pub(crate) mod spool;
+
+/// In-process consumers of the recorded queries, served by the API.
+#[derive(Clone)]
+pub(crate) struct Recorders {
+  pub(crate) aggregates: Arc<Aggregates>,
+  pub(crate) query_log: Option<Arc<QueryLog>>,
+}
+
+pub(crate) struct Sink {
+  pub(crate) influx: Influx,
+  /// Shared with the sink replacing this one on reload.
+  pub(crate) spool: Option<Arc<Spool>>,
+}
+
+pub(crate) struct BufferConfig {
+  pub(crate) capacity: usize,
+  pub(crate) batch_size: usize,
+  pub(crate) overflow_policy: OverflowPolicy,
+  pub(crate) sample_rate: u64,
+  pub(crate) flush_interval: Duration,
+  pub(crate) max_retries: u32,
+  pub(crate) initial_backoff: Duration,
+  pub(crate) max_backoff: Duration,
+}
+
+#[derive(Clone)]
+struct Entry {
+  timestamp: SystemTime,
+  src: Client,
+  src_name: Option<String>,
+  src_group: Option<Arc<str>>,
+  protocol: Protocol,
+  query: Option<LowerName>,
+  query_type: RecordType,
+  response_code: ResponseCode,
+  blocked: bool,
+  duration: Duration,
+}
+
+pub(crate) struct Stats<T>(Arc<InnerStats<T>>);
+
+struct InnerStats<T> {
+  sink: Swap<Option<Sink>>,
+  buffer: Buffer<Entry>,
+  config: BufferConfig,
+  failed: AtomicU64,
+  privacy: Privacy,
+  recorders: Recorders,
+  hostnames: Option<Hostnames>,
+  delegate: T,
+  blacklist: Swap<Blacklist>,
+  client_groups: Swap<ClientGroups>,
+  shutdown: Shutdown,
+  /// Its probes are answered but not recorded.
+  readiness: Arc<Readiness>,
+}
+
+impl Entry {
+  /// Response code, or `Blocked` if the query never reached an authority.
+  fn status(&self) -> &str {
+    if self.blocked {
+      "Blocked"
+    } else {
+      self.response_code.to_str()
+    }
+  }
+
+  fn write<W: Write>(&self, w: &mut W, influx: &Influx) -> anyhow::Result<()> {
+    let line = Line::new("queries")
+      .tag("src", &self.src)
+      .tag("src_name", self.src_name.as_deref().unwrap_or_default())
+      .tag("src_group", self.src_group.as_deref().unwrap_or_default())
+      .tag("protocol", self.protocol)
+      .tag(
+        "query",
+        self
+          .query
+          .as_ref()
+          .map_or(String::new(), ToString::to_string),
+      )
+      .tag("type", self.query_type)
+      .tag("response_code", self.status())
+      .tag("blocked", self.blocked);
+
+    // in milliseconds and unsigned, as written before other InfluxDB
+    // versions were supported, so existing series keep their field type
+    let duration = self.duration.as_millis() as u64;
+    let line = if influx.supports_unsigned() {
+      line.unsigned("duration", duration)
+    } else {
+      line.field("duration", duration as i64)
+    };
+    line.write(w, influx.precision().timestamp(self.timestamp)?)?;
+
+    Ok(())
+  }
+}
+
+impl<T: RequestHandler> Stats<T> {
+  pub(crate) fn new(
+    delegate: T,
+    blacklist: Swap<Blacklist>,
+    client_groups: Swap<ClientGroups>,
+    sink: Swap<Option<Sink>>,
+    config: BufferConfig,
+    privacy: Privacy,
+    recorders: Recorders,
+    hostnames: Option<Hostnames>,
+    shutdown: Shutdown,
+    readiness: Arc<Readiness>,
+  ) -> Self {
+    Self(Arc::new(InnerStats {
+      sink,
+      buffer: Buffer::new(
+        config.capacity,
+        config.batch_size,
+        config.overflow_policy,
+        config.sample_rate,
+      ),
+      config,
+      failed: AtomicU64::new(0),
+      privacy,
+      recorders,
+      hostnames,
+      delegate,
+      blacklist,
+      client_groups,
+      shutdown,
+      readiness,
+    }))
+  }
+
+  fn push(&self, entry: Entry) {
+    self.0.recorders.aggregates.record(&entry);
+    if let Some(query_log) = &self.0.recorders.query_log {
+      query_log.record(&entry);
+    }
+
+    if self.0.sink.load().is_some() {
+      self.0.buffer.push(entry);
+    }
+  }
+
+  /// Ships buffered entries until shutdown, either every `flush_interval`
+  /// or as soon as a full batch is available. The sink is looked up every
+  /// time, as it may be replaced on reload.
+  pub(crate) async fn run(&self) {
+    let mut dropped = 0;
+
+    loop {
+      select! {
+        _ = self.0.buffer.batch_ready() => {},
+        _ = tokio::time::sleep(self.0.config.flush_interval) => {},
+        _ = self.0.shutdown.triggered() => return,
+      }
+
+      let sink = self.0.sink.load();
+      let Some(sink) = sink.as_ref() else {
+        continue;
+      };
+
+      if let Err(err) = self.replay(sink).await {
+        warn!("Unable to replay spooled stats: {}", err);
+      }
+
+      while self.0.buffer.len() > 0 {
+        if let Err(err) = self.flush(sink).await {
+          error!("Unable to write stats: {}", err);
+        }
+      }
+
+      let total = self.dropped();
+      if total > dropped {
+        warn!(
+          "Dropped {} stats entries ({} total, {} buffered)",
+          total - dropped,
+          total,
+          self.0.buffer.len()
+        );
+        dropped = total;
+      }
+    }
+  }
+
+  /// Ships the entries still buffered on shutdown, once `run` returned.
+  /// Spooled batches are left for the next start, anything the sink fails
+  /// to take is spooled if possible.
+  pub(crate) async fn flush_all(&self) {
+    let sink = self.0.sink.load();
+    let Some(sink) = sink.as_ref() else {
+      return;
+    };
+
+    while self.0.buffer.len() > 0 {
+      if let Err(err) = self.flush(sink).await {
+        error!("Unable to write stats: {}", err);
+      }
+    }
+  }
+
+  /// Writes one batch, retrying with exponential backoff until shutdown. A
+  /// batch that still fails after `max_retries` retries is spooled to disk
+  /// if configured, otherwise it is dropped and counted, as is a batch the
+  /// sink rejects.
+  async fn flush(&self, sink: &Sink) -> anyhow::Result<()> {
+    let entries = self.0.buffer.drain();
+
+    if entries.is_empty() {
+      return Ok(());
+    }
+
+    let mut buf = Vec::new();
+    {
+      let mut encoder = GzEncoder::new(&mut buf, Compression::default());
+
+      for entry in &entries {
+        entry.write(&mut encoder, &sink.influx)?;
+      }
+    }
+
+    let count = entries.len() as u64;
+
+    if let Some(spool) = &sink.spool {
+      // older batches are still waiting on disk, queue up behind them to keep the order
+      if !spool.is_empty().await {
+        return self.spool(spool, &buf, count).await;
+      }
+    }
+
+    match self.send_with_retry(&sink.influx, &buf).await {
+      Ok(()) => Ok(()),
+      Err(err) if err.is::<Rejected>() => {
+        self.0.failed.fetch_add(count, Ordering::Relaxed);
+        Err(err)
+      }
+      Err(err) => match &sink.spool {
+        Some(spool) => {
+          warn!("Unable to write stats, spooling batch: {}", err);
+          self.spool(spool, &buf, count).await
+        }
+        None => {
+          self.0.failed.fetch_add(count, Ordering::Relaxed);
+          Err(err)
+        }
+      },
+    }
+  }
+
+  async fn send_with_retry(&self, influx: &Influx, body: &[u8]) -> anyhow::Result<()> {
+    let mut backoff = self.0.config.initial_backoff;
+    let mut attempt = 0;
+
+    loop {
+      match influx.write(body.to_vec()).await {
+        Ok(()) => return Ok(()),
+        // on shutdown the batch is spooled rather than retried
+        Err(err)
+          if attempt < self.0.config.max_retries
+            && !err.is::<Rejected>()
+            && !self.0.shutdown.is_triggered() =>
+        {
+          attempt += 1;
+          warn!(
+            "Unable to write stats, retrying in {:?} ({}/{}): {}",
+            backoff, attempt, self.0.config.max_retries, err
+          );
+          select! {
+            _ = tokio::time::sleep(backoff) => {},
+            _ = self.0.shutdown.triggered() => {},
+          }
+          backoff = (backoff * 2).min(self.0.config.max_backoff);
+        }
+        Err(err) => return Err(err),
+      }
+    }
+  }
+
+  async fn spool(&self, spool: &Spool, body: &[u8], entries: u64) -> anyhow::Result<()> {
+    match spool.push(body, entries).await {
+      Ok(evicted) => {
+        self.0.failed.fetch_add(evicted, Ordering::Relaxed);
+        Ok(())
+      }
+      Err(err) => {
+        self.0.failed.fetch_add(entries, Ordering::Relaxed);
+        Err(err)
+      }
+    }
+  }
+
+  /// Delivers spooled batches oldest first and stops at the first failure
+  /// the sink may recover from. Batches it rejects are dropped and counted,
+  /// so they can't hold up the ones behind them.
+  async fn replay(&self, sink: &Sink) -> anyhow::Result<()> {
+    let Some(spool) = &sink.spool else {
+      return Ok(());
+    };
+
+    while let Some(body) = spool.front().await? {
+      match sink.influx.write(body).await {
+        Ok(()) => {
+          spool.pop_front().await?;
+        }
+        Err(err) if err.is::<Rejected>() => {
+          let entries = spool.pop_front().await?;
+          self.0.failed.fetch_add(entries, Ordering::Relaxed);
+          warn!(
+            "Dropped spooled stats batch of {} entries: {}",
+            entries, err
+          );
+        }
+        Err(err) => return Err(err),
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Number of entries lost to buffer overflow, failed writes or spool eviction.
+  pub(crate) fn dropped(&self) -> u64 {
+    self.0.buffer.dropped() + self.0.failed.load(Ordering::Relaxed)
+  }
+
+  /// Answers blocked queries with NXDOMAIN and hands everything else to the
+  /// delegate. Nothing is blocked for clients of groups bypassing the
+  /// blocklist.
+  async fn respond<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    mut response_handle: R,
+    blocking: bool,
+  ) -> (ResponseInfo, bool) {
+    let blocked = blocking
+      && info_span!("blocklist")
+        .in_scope(|| self.0.blacklist.load().is_blocked(request.query().name()));
+
+    let response = if blocked {
+      let builder = MessageResponseBuilder::from_message_request(request);
+      match response_handle
+        .send_response(builder.error_msg(request.header(), ResponseCode::NXDomain))
+        .await
+      {
+        Ok(resp) => resp,
+        Err(err) => todo!(),
+      }
+    } else {
+      self
+        .0
+        .delegate
+        .handle_request(request, response_handle)
+        .instrument(info_span!("catalog", cache = field::Empty))
+        .await
+    };
+
+    (response, blocked)
+  }
+
+  pub(crate) fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+#[async_trait]
+impl<T: RequestHandler> RequestHandler for Stats<T> {
+  async fn handle_request<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+  ) -> ResponseInfo {
+    let timestamp = SystemTime::now();
+
+    let src = self.0.privacy.client(request.src().ip());
+    let query = self.0.privacy.query(request.query().name());
+
+    let client_groups = self.0.client_groups.load();
+    let group = client_groups.group_of(request.src().ip());
+    let blocking = group.map_or(true, ClientGroup::is_blocking);
+    // the group is as coarse as its networks, but not recorded where the
+    // client isn't at all
+    let src_group = match src {
+      Client::Anonymous => None,
+      _ => group.map(|group| group.name().clone()),
+    };
+    // names would defeat any anonymization of the client address
+    let src_name = match &self.0.hostnames {
+      Some(hostnames) if self.0.privacy.identifies_clients() => hostnames.get(request.src().ip()),
+      _ => None,
+    };
+
+    let span = info_span!(
+      "request",
+      client = %src,
+      client_group = field::Empty,
+      client_name = field::Empty,
+      protocol = %request.protocol(),
+      qname = field::Empty,
+      qtype = %request.query().query_type(),
+      rcode = field::Empty,
+    );
+    if let Some(query) = &query {
+      span.record("qname", field::display(query));
+    }
+    if let Some(src_group) = &src_group {
+      span.record("client_group", field::display(src_group));
+    }
+    if let Some(src_name) = &src_name {
+      span.record("client_name", field::display(src_name));
+    }
+
+    let (response, blocked) = self
+      .respond(request, response_handle, blocking)
+      .instrument(span.clone())
+      .await;
+
+    let duration = timestamp.elapsed().unwrap();
+
+    let entry = Entry {
+      timestamp,
+      src,
+      src_name,
+      src_group,
+      protocol: request.protocol(),
+      query,
+      query_type: request.query().query_type(),
+      response_code: response.response_code(),
+      duration,
+      blocked,
+    };
+    span.record("rcode", entry.status());
+
+    let probe = self
+      .0
+      .readiness
+      .is_probe(request.src(), request.query().name());
+    if !probe && self.0.privacy.record(blocked) {
+      self.push(entry);
+    }
+
+    response
+  }
+}
@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::stats::Entry;
+
+const TAIL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Serialize)]
+pub(crate) struct LoggedQuery {
+  /// Milliseconds since the unix epoch.
+  timestamp: u64,
+  client: String,
+  client_name: Option<String>,
+  client_group: Option<String>,
+  protocol: String,
+  query: Option<String>,
+  query_type: String,
+  response_code: String,
+  blocked: bool,
+  duration_us: u64,
+}
+
+impl LoggedQuery {
+  fn size(&self) -> usize {
+    size_of::<Self>()
+      + self.client.len()
+      + self.client_name.as_ref().map_or(0, String::len)
+      + self.client_group.as_ref().map_or(0, String::len)
+      + self.protocol.len()
+      + self.query.as_ref().map_or(0, String::len)
+      + self.query_type.len()
+      + self.response_code.len()
+  }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Filter {
+  /// Client address, name or group.
+  client: Option<String>,
+  /// Substring of the query name.
+  qname: Option<String>,
+  qtype: Option<String>,
+  rcode: Option<String>,
+  blocked: Option<bool>,
+  /// Milliseconds since the unix epoch, inclusive.
+  since: Option<u64>,
+  /// Milliseconds since the unix epoch, exclusive.
+  until: Option<u64>,
+  pub(crate) limit: Option<usize>,
+}
+
+impl Filter {
+  pub(crate) fn matches(&self, query: &LoggedQuery) -> bool {
+    if let Some(client) = &self.client {
+      if query.client != *client
+        && query.client_name.as_ref() != Some(client)
+        && query.client_group.as_ref() != Some(client)
+      {
+        return false;
+      }
+    }
+
+    if let Some(qname) = &self.qname {
+      let qname = qname.to_lowercase();
+      if !query.query.as_ref().map_or(false, |q| q.contains(&qname)) {
+        return false;
+      }
+    }
+
+    if let Some(qtype) = &self.qtype {
+      if !query.query_type.eq_ignore_ascii_case(qtype) {
+        return false;
+      }
+    }
+
+    if let Some(rcode) = &self.rcode {
+      if !query.response_code.eq_ignore_ascii_case(rcode) {
+        return false;
+      }
+    }
+
+    self
+      .blocked
+      .map_or(true, |blocked| query.blocked == blocked)
+      && self.since.map_or(true, |since| query.timestamp >= since)
+      && self.until.map_or(true, |until| query.timestamp < until)
+  }
+}
+
+/// Ring buffer of the most recent queries, bounded by count and memory.
+pub(crate) struct QueryLog {
+  capacity: usize,
+  max_memory: usize,
+  state: Mutex<State>,
+  tail: broadcast::Sender<LoggedQuery>,
+}
+
+struct State {
+  queries: VecDeque<LoggedQuery>,
+  memory: usize,
+}
+
+impl QueryLog {
+  pub(crate) fn new(capacity: usize, max_memory: usize) -> Self {
+    Self {
+      capacity,
+      max_memory,
+      state: Mutex::new(State {
+        queries: VecDeque::new(),
+        memory: 0,
+      }),
+      tail: broadcast::channel(TAIL_CAPACITY).0,
+    }
+  }
+
+  pub(crate) fn record(&self, entry: &Entry) {
+    let query = LoggedQuery {
+      timestamp: entry
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64),
+      client: entry.src.to_string(),
+      client_name: entry.src_name.clone(),
+      client_group: entry.src_group.as_deref().map(str::to_string),
+      protocol: entry.protocol.to_string(),
+      query: entry.query.as_ref().map(ToString::to_string),
+      query_type: entry.query_type.to_string(),
+      response_code: entry.status().to_string(),
+      blocked: entry.blocked,
+      duration_us: entry.duration.as_micros() as u64,
+    };
+
+    if self.tail.receiver_count() > 0 {
+      // a send only fails if the last receiver went away in the meantime
+      let _ = self.tail.send(query.clone());
+    }
+
+    let mut state = self.state.lock().unwrap();
+    state.memory += query.size();
+    state.queries.push_back(query);
+
+    while state.queries.len() > self.capacity || state.memory > self.max_memory {
+      match state.queries.pop_front() {
+        Some(evicted) => state.memory -= evicted.size(),
+        None => break,
+      }
+    }
+  }
+
+  /// Matching queries, newest first.
+  pub(crate) fn search(&self, filter: &Filter, limit: usize) -> Vec<LoggedQuery> {
+    let state = self.state.lock().unwrap();
+
+    state
+      .queries
+      .iter()
+      .rev()
+      .filter(|query| filter.matches(query))
+      .take(limit)
+      .cloned()
+      .collect()
+  }
+
+  pub(crate) fn subscribe(&self) -> broadcast::Receiver<LoggedQuery> {
+    self.tail.subscribe()
+  }
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const EXTENSION: &str = "batch";
+const TMP_EXTENSION: &str = "tmp";
+
+/// Disk-backed queue of encoded batches the sink did not accept.
+///
+/// Every batch is an append-only segment named `<sequence>-<entries>.batch`,
+/// so the order and the number of contained entries survive restarts. Once
+/// the spool exceeds its size cap the oldest segments are evicted.
+pub(crate) struct Spool {
+  dir: PathBuf,
+  max_size: u64,
+  state: Mutex<State>,
+}
+
+struct State {
+  segments: VecDeque<Segment>,
+  size: u64,
+  next: u64,
+}
+
+struct Segment {
+  sequence: u64,
+  entries: u64,
+  size: u64,
+}
+
+impl Segment {
+  fn path(&self, dir: &Path) -> PathBuf {
+    dir.join(format!(
+      "{:020}-{}.{}",
+      self.sequence, self.entries, EXTENSION
+    ))
+  }
+
+  fn parse(path: &Path, size: u64) -> Option<Self> {
+    if path.extension()? != EXTENSION {
+      return None;
+    }
+
+    let (sequence, entries) = path.file_stem()?.to_str()?.split_once('-')?;
+
+    Some(Self {
+      sequence: sequence.parse().ok()?,
+      entries: entries.parse().ok()?,
+      size,
+    })
+  }
+}
+
+impl Spool {
+  pub(crate) async fn open(dir: PathBuf, max_size: u64) -> anyhow::Result<Self> {
+    fs::create_dir_all(&dir).await?;
+
+    let mut segments = Vec::new();
+    let mut read_dir = fs::read_dir(&dir).await?;
+
+    while let Some(entry) = read_dir.next_entry().await? {
+      let path = entry.path();
+      if path.extension().map_or(false, |ext| ext == TMP_EXTENSION) {
+        fs::remove_file(&path).await?;
+        continue;
+      }
+
+      match Segment::parse(&path, entry.metadata().await?.len()) {
+        Some(segment) => segments.push(segment),
+        None => warn!("Ignoring unknown file {} in stats spool", path.display()),
+      }
+    }
+
+    segments.sort_by_key(|segment| segment.sequence);
+
+    let size = segments.iter().map(|segment| segment.size).sum();
+    let next = segments.last().map_or(0, |segment| segment.sequence + 1);
+
+    if !segments.is_empty() {
+      info!(
+        "Found {} spooled stats batches ({} bytes) in {}",
+        segments.len(),
+        size,
+        dir.display()
+      );
+    }
+
+    Ok(Self {
+      dir,
+      max_size,
+      state: Mutex::new(State {
+        segments: VecDeque::from(segments),
+        size,
+        next,
+      }),
+    })
+  }
+
+  pub(crate) fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Appends a batch and returns the number of entries evicted to stay
+  /// within the size cap.
+  pub(crate) async fn push(&self, body: &[u8], entries: u64) -> anyhow::Result<u64> {
+    let size = body.len() as u64;
+    if size > self.max_size {
+      return Err(anyhow!(
+        "Batch of {} bytes exceeds the spool size of {} bytes",
+        size,
+        self.max_size
+      ));
+    }
+
+    let mut state = self.state.lock().await;
+
+    let mut evicted = 0;
+    while state.size + size > self.max_size {
+      let Some(segment) = state.segments.front() else {
+        break;
+      };
+      // only forget the segment once it is gone, a failed removal is retried
+      fs::remove_file(segment.path(&self.dir)).await?;
+      let segment = state.segments.pop_front().unwrap();
+      state.size -= segment.size;
+      evicted += segment.entries;
+    }
+
+    let segment = Segment {
+      sequence: state.next,
+      entries,
+      size,
+    };
+
+    // write next to the segment, sync and rename, so a crash never leaves a
+    // torn batch behind
+    let path = segment.path(&self.dir);
+    let tmp = path.with_extension(TMP_EXTENSION);
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(body).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp, &path).await?;
+    // persist the rename itself
+    #[cfg(unix)]
+    fs::File::open(&self.dir).await?.sync_all().await?;
+
+    state.next += 1;
+    state.size += size;
+    state.segments.push_back(segment);
+
+    Ok(evicted)
+  }
+
+  /// Reads the oldest batch without removing it.
+  pub(crate) async fn front(&self) -> anyhow::Result<Option<Vec<u8>>> {
+    let state = self.state.lock().await;
+
+    match state.segments.front() {
+      Some(segment) => Ok(Some(fs::read(segment.path(&self.dir)).await?)),
+      None => Ok(None),
+    }
+  }
+
+  /// Removes the oldest batch once it has been delivered or rejected and
+  /// returns the number of entries it contained.
+  pub(crate) async fn pop_front(&self) -> anyhow::Result<u64> {
+    let mut state = self.state.lock().await;
+
+    let Some(segment) = state.segments.front() else {
+      return Ok(0);
+    };
+    fs::remove_file(segment.path(&self.dir)).await?;
+    let segment = state.segments.pop_front().unwrap();
+    state.size -= segment.size;
+
+    Ok(segment.entries)
+  }
+
+  pub(crate) async fn is_empty(&self) -> bool {
+    self.state.lock().await.segments.is_empty()
+  }
+}
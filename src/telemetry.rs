@@ -0,0 +1,43 @@
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::{runtime, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Layer exporting spans to an OTLP/gRPC collector, sampling `sample_ratio`
+/// of the root spans (child spans follow their parent).
+pub(crate) fn otlp_layer<S>(
+  endpoint: &str,
+  sample_ratio: f64,
+) -> anyhow::Result<OpenTelemetryLayer<S, Tracer>>
+where
+  S: Subscriber + for<'span> LookupSpan<'span>,
+{
+  let tracer = opentelemetry_otlp::new_pipeline()
+    .tracing()
+    .with_exporter(
+      opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint),
+    )
+    .with_trace_config(
+      trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+          sample_ratio,
+        ))))
+        .with_resource(Resource::new(vec![
+          KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+          KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])),
+    )
+    .install_batch(runtime::Tokio)?;
+
+  Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Flushes spans that are still buffered by the batch exporter.
+pub(crate) fn shutdown() {
+  opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,43 @@
+use std::net::{IpAddr, SocketAddr};
+
+use trust_dns_server::resolver::config::{
+  NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
+};
+use trust_dns_server::resolver::error::ResolveError;
+use trust_dns_server::resolver::TokioAsyncResolver;
+
+/// Resolves the hostnames of upstreams, which can't be resolved through the
+/// upstreams themselves.
+pub(crate) struct Bootstrap {
+  resolver: TokioAsyncResolver,
+}
+
+impl Bootstrap {
+  pub(crate) fn new(servers: &[SocketAddr]) -> Self {
+    let name_servers = servers
+      .iter()
+      .flat_map(|addr| {
+        [Protocol::Udp, Protocol::Tcp].map(|protocol| NameServerConfig {
+          socket_addr: *addr,
+          protocol,
+          tls_dns_name: None,
+          trust_negative_responses: true,
+          tls_config: None,
+          bind_addr: None,
+        })
+      })
+      .collect::<Vec<_>>();
+
+    Self {
+      resolver: TokioAsyncResolver::tokio(
+        ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(name_servers)),
+        ResolverOpts::default(),
+      ),
+    }
+  }
+
+  pub(crate) async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+    let lookup = self.resolver.lookup_ip(host).await?;
+    Ok(lookup.iter().collect())
+  }
+}
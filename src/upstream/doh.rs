@@ -0,0 +1,164 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::Client;
+use rustls::ClientConfig;
+use trust_dns_server::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_server::proto::rr::rdata::SOA;
+use trust_dns_server::proto::rr::{LowerName, Name, RData, Record, RecordType};
+use trust_dns_server::resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_server::resolver::lookup::Lookup;
+use url::{Host, Url};
+
+pub(super) const DNS_MESSAGE: &str = "application/dns-message";
+
+/// DNS over HTTPS (RFC 8484) client for upstreams addressed by URL, so any
+/// path can be used. The hostname is resolved by the bootstrap resolvers.
+pub(crate) struct DohClient {
+  url: Url,
+  host: String,
+  timeout: Duration,
+  tls_config: Option<Arc<ClientConfig>>,
+  client: RwLock<Client>,
+}
+
+impl DohClient {
+  pub(crate) fn new(
+    url: Url,
+    timeout: Duration,
+    tls_config: Option<Arc<ClientConfig>>,
+  ) -> anyhow::Result<Self> {
+    let host = match url.host() {
+      Some(Host::Domain(domain)) => domain.to_string(),
+      Some(Host::Ipv4(ip)) => ip.to_string(),
+      Some(Host::Ipv6(ip)) => ip.to_string(),
+      None => return Err(anyhow::anyhow!("Missing host in upstream {}", url)),
+    };
+
+    let client = Self::client(&host, &[], timeout, tls_config.as_deref())?;
+
+    Ok(Self {
+      url,
+      host,
+      timeout,
+      tls_config,
+      client: RwLock::new(client),
+    })
+  }
+
+  pub(crate) fn host(&self) -> &str {
+    &self.host
+  }
+
+  /// Connects to the given addresses from now on.
+  pub(crate) fn set_addrs(&self, addrs: &[IpAddr]) -> anyhow::Result<()> {
+    let port = self.url.port_or_known_default().unwrap_or(443);
+    let addrs = addrs
+      .iter()
+      .map(|ip| SocketAddr::new(*ip, port))
+      .collect::<Vec<_>>();
+
+    *self.client.write().unwrap() =
+      Self::client(&self.host, &addrs, self.timeout, self.tls_config.as_deref())?;
+    Ok(())
+  }
+
+  fn client(
+    host: &str,
+    addrs: &[SocketAddr],
+    timeout: Duration,
+    tls_config: Option<&ClientConfig>,
+  ) -> anyhow::Result<Client> {
+    let builder = match tls_config {
+      Some(tls_config) => Client::builder().use_preconfigured_tls(tls_config.clone()),
+      None => Client::builder().use_rustls_tls(),
+    };
+
+    Ok(
+      builder
+        .timeout(timeout)
+        .resolve_to_addrs(host, addrs)
+        .build()?,
+    )
+  }
+
+  pub(crate) async fn lookup(
+    &self,
+    name: &LowerName,
+    rtype: RecordType,
+  ) -> Result<Lookup, ResolveError> {
+    let (query, request) = request(name, rtype);
+
+    let client = self.client.read().unwrap().clone();
+    let body = client
+      .post(self.url.clone())
+      .header(CONTENT_TYPE, DNS_MESSAGE)
+      .header(ACCEPT, DNS_MESSAGE)
+      .body(request.to_vec()?)
+      .send()
+      .await
+      .and_then(|response| response.error_for_status())
+      .map_err(|err| ResolveError::from(err.to_string()))?
+      .bytes()
+      .await
+      .map_err(|err| ResolveError::from(err.to_string()))?;
+
+    response(query, &body)
+  }
+}
+
+/// Query message of a lookup, shared by all DNS over HTTPS versions.
+pub(super) fn request(name: &LowerName, rtype: RecordType) -> (Query, Message) {
+  let query = Query::query(Name::from(name.clone()), rtype);
+
+  let mut request = Message::new();
+  // an id of 0 keeps responses cacheable by HTTP caches (RFC 8484 4.1)
+  request
+    .set_id(0)
+    .set_message_type(MessageType::Query)
+    .set_op_code(OpCode::Query)
+    .set_recursion_desired(true)
+    .add_query(query.clone());
+
+  (query, request)
+}
+
+/// Converts the response body to the result of the lookup, negative
+/// answers to the errors the resolver would return.
+pub(super) fn response(query: Query, body: &[u8]) -> Result<Lookup, ResolveError> {
+  let response = Message::from_vec(body)?;
+
+  match response.response_code() {
+    ResponseCode::NoError if !response.answers().is_empty() => Ok(Lookup::new_with_max_ttl(
+      query,
+      Arc::from(response.answers()),
+    )),
+    response_code @ (ResponseCode::NoError | ResponseCode::NXDomain) => {
+      let soa = response
+        .name_servers()
+        .iter()
+        .find(|record| matches!(record.data(), Some(RData::SOA(_))))
+        .cloned()
+        .and_then(|record| Record::<SOA>::try_from(record).ok());
+
+      Err(
+        ResolveErrorKind::NoRecordsFound {
+          query: Box::new(query),
+          negative_ttl: soa
+            .as_ref()
+            .and_then(|soa| Some(soa.ttl().min(soa.data()?.minimum()))),
+          soa: soa.map(Box::new),
+          response_code,
+          trusted: true,
+        }
+        .into(),
+      )
+    }
+    response_code => Err(ResolveError::from(format!(
+      "Upstream responded with {}",
+      response_code
+    ))),
+  }
+}
@@ -0,0 +1,201 @@
+use std::future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bytes::{Buf, Bytes, BytesMut};
+use h3::client::SendRequest;
+use h3_quinn::OpenStreams;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use quinn::Endpoint;
+use trust_dns_server::proto::rr::{LowerName, RecordType};
+use trust_dns_server::resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_server::resolver::lookup::Lookup;
+use url::{Host, Url};
+
+use crate::upstream::doh::{request, response, DNS_MESSAGE};
+
+/// DNS over HTTP/3 client. The connection is kept open and reused until a
+/// request on it fails.
+pub(crate) struct Doh3Client {
+  url: Url,
+  /// Name the certificate is verified against.
+  server_name: String,
+  /// Whether the addresses are resolved by the bootstrap resolvers.
+  resolved: bool,
+  timeout: Duration,
+  config: quinn::ClientConfig,
+  addrs: RwLock<Vec<SocketAddr>>,
+  connection: tokio::sync::Mutex<Option<SendRequest<OpenStreams, Bytes>>>,
+}
+
+impl Doh3Client {
+  /// Client for an upstream at a fixed address, asked at `/dns-query`.
+  pub(crate) fn with_addr(
+    addr: SocketAddr,
+    server_name: String,
+    timeout: Duration,
+    tls_config: rustls::ClientConfig,
+  ) -> anyhow::Result<Self> {
+    let url = Url::parse(&format!("https://{}/dns-query", server_name))?;
+    Ok(Self::new(
+      url,
+      server_name,
+      false,
+      vec![addr],
+      timeout,
+      tls_config,
+    ))
+  }
+
+  /// Client for an upstream addressed by URL, the host is resolved by the
+  /// bootstrap resolvers.
+  pub(crate) fn with_url(
+    url: Url,
+    timeout: Duration,
+    tls_config: rustls::ClientConfig,
+  ) -> anyhow::Result<Self> {
+    // the url is sent in the request, the scheme of h3 upstreams is https
+    let request_url = Url::parse(&url.as_str().replacen("h3:", "https:", 1))?;
+    let server_name = match request_url.host() {
+      Some(Host::Domain(domain)) => domain.to_string(),
+      Some(Host::Ipv4(ip)) => ip.to_string(),
+      Some(Host::Ipv6(ip)) => ip.to_string(),
+      None => return Err(anyhow!("Missing host in upstream {}", url)),
+    };
+
+    Ok(Self::new(
+      request_url,
+      server_name,
+      true,
+      vec![],
+      timeout,
+      tls_config,
+    ))
+  }
+
+  fn new(
+    url: Url,
+    server_name: String,
+    resolved: bool,
+    addrs: Vec<SocketAddr>,
+    timeout: Duration,
+    tls_config: rustls::ClientConfig,
+  ) -> Self {
+    Self {
+      url,
+      server_name,
+      resolved,
+      timeout,
+      config: quinn::ClientConfig::new(Arc::new(tls_config)),
+      addrs: RwLock::new(addrs),
+      connection: tokio::sync::Mutex::new(None),
+    }
+  }
+
+  /// Host to resolve through the bootstrap resolvers, if any.
+  pub(crate) fn host(&self) -> Option<&str> {
+    self.resolved.then_some(self.server_name.as_str())
+  }
+
+  /// Connects to the given addresses from now on.
+  pub(crate) fn set_addrs(&self, addrs: &[IpAddr]) {
+    let port = self.url.port().unwrap_or(443);
+    *self.addrs.write().unwrap() = addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+
+    // the next request connects to the new addresses
+    if let Ok(mut connection) = self.connection.try_lock() {
+      *connection = None;
+    }
+  }
+
+  pub(crate) async fn lookup(
+    &self,
+    name: &LowerName,
+    rtype: RecordType,
+  ) -> Result<Lookup, ResolveError> {
+    let (query, request) = request(name, rtype);
+    let request = request.to_vec()?;
+
+    let body = match tokio::time::timeout(self.timeout, self.exchange(request)).await {
+      Ok(Ok(body)) => body,
+      Ok(Err(err)) => {
+        *self.connection.lock().await = None;
+        return Err(ResolveError::from(err.to_string()));
+      }
+      Err(_) => {
+        *self.connection.lock().await = None;
+        return Err(ResolveErrorKind::Timeout.into());
+      }
+    };
+
+    response(query, &body)
+  }
+
+  async fn exchange(&self, body: Vec<u8>) -> anyhow::Result<Bytes> {
+    let mut send_request = self.connection().await?;
+
+    let request = http::Request::post(self.url.as_str())
+      .header(CONTENT_TYPE, DNS_MESSAGE)
+      .header(ACCEPT, DNS_MESSAGE)
+      .body(())?;
+
+    let mut stream = send_request.send_request(request).await?;
+    stream.send_data(Bytes::from(body)).await?;
+    stream.finish().await?;
+
+    let response = stream.recv_response().await?;
+    if !response.status().is_success() {
+      return Err(anyhow!("Upstream responded with {}", response.status()));
+    }
+
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+      body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    Ok(body.freeze())
+  }
+
+  /// The open connection, or a new one to the first address that accepts.
+  async fn connection(&self) -> anyhow::Result<SendRequest<OpenStreams, Bytes>> {
+    let mut connection = self.connection.lock().await;
+    if let Some(send_request) = connection.as_ref() {
+      return Ok(send_request.clone());
+    }
+
+    let addrs = self.addrs.read().unwrap().clone();
+    let mut last = Err(anyhow!("No addresses for upstream {}", self.url));
+    for addr in addrs {
+      last = self.connect(addr).await;
+      if last.is_ok() {
+        break;
+      }
+    }
+
+    let send_request = last?;
+    *connection = Some(send_request.clone());
+    Ok(send_request)
+  }
+
+  async fn connect(&self, addr: SocketAddr) -> anyhow::Result<SendRequest<OpenStreams, Bytes>> {
+    let bind_addr = match addr {
+      SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+      SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+
+    let mut endpoint = Endpoint::client(bind_addr)?;
+    endpoint.set_default_client_config(self.config.clone());
+
+    let connection = endpoint.connect(addr, &self.server_name)?.await?;
+    let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(connection)).await?;
+
+    // drives the connection until it is closed
+    tokio::spawn(async move {
+      let _ = future::poll_fn(|cx| driver.poll_close(cx)).await;
+    });
+
+    Ok(send_request)
+  }
+}
@@ -0,0 +1,106 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+pub(crate) struct HealthConfig {
+  /// Consecutive failures after which an upstream is taken out of rotation.
+  pub(crate) failure_threshold: u32,
+  /// How long a failed upstream stays out of rotation before it is tried again.
+  pub(crate) open_duration: Duration,
+}
+
+/// Circuit breaker and latency estimate of a single upstream.
+///
+/// After `failure_threshold` consecutive failures the circuit opens and the
+/// upstream is skipped for `open_duration`. Afterwards it gets another
+/// chance: one failure opens the circuit again, one success closes it.
+pub(crate) struct Health {
+  failure_threshold: u32,
+  open_duration: Duration,
+  state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+  consecutive_failures: u32,
+  open_until: Option<Instant>,
+  latency: Option<Duration>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct HealthStats {
+  healthy: bool,
+  consecutive_failures: u32,
+  /// Moving average of the response time.
+  latency_us: Option<u64>,
+}
+
+/// What changed with a recorded outcome, for logging.
+pub(crate) enum Transition {
+  Opened,
+  Closed,
+  Unchanged,
+}
+
+impl Health {
+  pub(crate) fn new(config: &HealthConfig) -> Self {
+    Self {
+      failure_threshold: config.failure_threshold.max(1),
+      open_duration: config.open_duration,
+      state: Mutex::new(State::default()),
+    }
+  }
+
+  pub(crate) fn available(&self, now: Instant) -> bool {
+    let state = self.state.lock().unwrap();
+    state.open_until.map_or(true, |until| until <= now)
+  }
+
+  pub(crate) fn latency(&self) -> Option<Duration> {
+    self.state.lock().unwrap().latency
+  }
+
+  pub(crate) fn success(&self, latency: Duration) -> Transition {
+    let mut state = self.state.lock().unwrap();
+
+    state.latency = Some(match state.latency {
+      Some(average) => average * 7 / 8 + latency / 8,
+      None => latency,
+    });
+    state.consecutive_failures = 0;
+
+    match state.open_until.take() {
+      Some(_) => Transition::Closed,
+      None => Transition::Unchanged,
+    }
+  }
+
+  pub(crate) fn failure(&self) -> Transition {
+    let mut state = self.state.lock().unwrap();
+
+    state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+    if state.consecutive_failures < self.failure_threshold {
+      return Transition::Unchanged;
+    }
+
+    let was_open = state.open_until.is_some();
+    state.open_until = Some(Instant::now() + self.open_duration);
+
+    if was_open {
+      Transition::Unchanged
+    } else {
+      Transition::Opened
+    }
+  }
+
+  pub(crate) fn stats(&self) -> HealthStats {
+    let state = self.state.lock().unwrap();
+
+    HealthStats {
+      healthy: state.open_until.is_none(),
+      consecutive_failures: state.consecutive_failures,
+      latency_us: state.latency.map(|latency| latency.as_micros() as u64),
+    }
+  }
+}
@@ -0,0 +1,538 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+use futures_util::future::{join_all, select_ok};
+use rustls::ClientConfig;
+use serde::Serialize;
+use tracing::{debug, info, info_span, warn, Instrument};
+use trust_dns_server::proto::op::ResponseCode;
+use trust_dns_server::proto::rr::{LowerName, Name, RecordType};
+use trust_dns_server::resolver::config::{
+  NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts, TlsClientConfig,
+};
+use trust_dns_server::resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_server::resolver::lookup::Lookup;
+use trust_dns_server::resolver::TokioAsyncResolver;
+
+use crate::args::UpstreamDns;
+use crate::upstream::bootstrap::Bootstrap;
+use crate::upstream::doh::DohClient;
+use crate::upstream::doh3::Doh3Client;
+use crate::upstream::health::{Health, HealthConfig, HealthStats, Transition};
+
+pub(crate) mod bootstrap;
+mod doh;
+mod doh3;
+pub(crate) mod health;
+pub(crate) mod tls;
+
+#[derive(Clone, Copy, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Strategy {
+  /// Ask the upstreams in configured order, the next one only if the
+  /// previous failed.
+  Failover,
+  /// Spread queries evenly over the upstreams.
+  RoundRobin,
+  /// Ask the upstream with the lowest average latency first.
+  LowestLatency,
+  /// Ask all upstreams at once and use the first answer.
+  Race,
+}
+
+pub(crate) struct UpstreamConfig {
+  pub(crate) strategy: Strategy,
+  /// Timeout of a single query to a single upstream.
+  pub(crate) timeout: Duration,
+  pub(crate) health: HealthConfig,
+}
+
+/// The upstreams a zone is forwarded to.
+pub(crate) struct UpstreamGroup {
+  zone: LowerName,
+  strategy: Strategy,
+  upstreams: Vec<Upstream>,
+  next: AtomicUsize,
+}
+
+struct Upstream {
+  label: String,
+  transport: Transport,
+  /// Current addresses of upstreams addressed by hostname.
+  addrs: Mutex<Vec<IpAddr>>,
+  health: Health,
+  queries: AtomicU64,
+  failures: AtomicU64,
+}
+
+enum Transport {
+  /// Upstream at a fixed address.
+  Resolver(TokioAsyncResolver),
+  /// DNS over TLS or QUIC upstream addressed by hostname.
+  Host {
+    protocol: Protocol,
+    host: String,
+    port: u16,
+    timeout: Duration,
+    tls_config: Option<Arc<ClientConfig>>,
+    resolver: RwLock<TokioAsyncResolver>,
+  },
+  /// DNS over HTTPS upstream addressed by URL.
+  Https(DohClient),
+  /// DNS over HTTP/3 upstream, by URL or at a fixed address.
+  H3(Doh3Client),
+}
+
+#[derive(Serialize)]
+pub(crate) struct GroupStats {
+  zone: String,
+  strategy: Strategy,
+  upstreams: Vec<UpstreamStats>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct UpstreamStats {
+  upstream: String,
+  queries: u64,
+  failures: u64,
+  health: HealthStats,
+}
+
+impl UpstreamGroup {
+  pub(crate) fn new(
+    zone: Name,
+    upstreams: &[UpstreamDns],
+    config: &UpstreamConfig,
+  ) -> anyhow::Result<Self> {
+    Ok(Self {
+      zone: LowerName::from(zone),
+      strategy: config.strategy,
+      upstreams: upstreams
+        .iter()
+        .map(|upstream| Upstream::new(upstream, config))
+        .collect::<anyhow::Result<_>>()?,
+      next: AtomicUsize::new(0),
+    })
+  }
+
+  /// Whether any upstream is addressed by hostname.
+  pub(crate) fn has_hosts(&self) -> bool {
+    self
+      .upstreams
+      .iter()
+      .any(|upstream| upstream.transport.host().is_some())
+  }
+
+  /// Resolves the hostnames of upstreams through the bootstrap resolvers.
+  pub(crate) async fn resolve_hosts(&self, bootstrap: &Bootstrap) {
+    join_all(
+      self
+        .upstreams
+        .iter()
+        .map(|upstream| upstream.resolve_host(bootstrap)),
+    )
+    .await;
+  }
+
+  /// Resolves the hostnames of upstreams again every `interval`, so
+  /// upstreams that move to other addresses keep working.
+  pub(crate) async fn refresh_hosts(&self, bootstrap: &Bootstrap, interval: Duration) {
+    loop {
+      tokio::time::sleep(interval).await;
+      self.resolve_hosts(bootstrap).await;
+    }
+  }
+
+  pub(crate) fn zone(&self) -> &LowerName {
+    &self.zone
+  }
+
+  /// Resolves the query according to the strategy. Negative answers are
+  /// answers too, only errors make another upstream being asked.
+  pub(crate) async fn lookup(
+    &self,
+    name: &LowerName,
+    rtype: RecordType,
+  ) -> Result<Lookup, ResolveError> {
+    let candidates = self.candidates();
+
+    if let Strategy::Race = self.strategy {
+      if candidates.is_empty() {
+        return Err(ResolveErrorKind::NoConnections.into());
+      }
+
+      let lookups = candidates.into_iter().map(|upstream| {
+        Box::pin(async move {
+          let result = upstream.lookup(name, rtype).await;
+          if answered(&result) {
+            Ok(result)
+          } else {
+            Err(result)
+          }
+        })
+      });
+
+      return match select_ok(lookups).await {
+        Ok((result, _)) => result,
+        Err(result) => result,
+      };
+    }
+
+    let mut last = Err(ResolveErrorKind::NoConnections.into());
+    for upstream in candidates {
+      last = upstream.lookup(name, rtype).await;
+      if answered(&last) {
+        break;
+      }
+    }
+
+    last
+  }
+
+  /// Upstreams in the order they should be asked. Upstreams with an open
+  /// circuit come last, or are left out when racing, unless there are no
+  /// others.
+  fn candidates(&self) -> Vec<&Upstream> {
+    let now = Instant::now();
+    let (mut available, unavailable): (Vec<_>, Vec<_>) = self
+      .upstreams
+      .iter()
+      .partition(|upstream| upstream.health.available(now));
+
+    match self.strategy {
+      Strategy::Failover | Strategy::Race => {}
+      Strategy::RoundRobin => {
+        if !available.is_empty() {
+          let start = self.next.fetch_add(1, Ordering::Relaxed) % available.len();
+          available.rotate_left(start);
+        }
+      }
+      // upstreams without any measurement yet go first to get one
+      Strategy::LowestLatency => available.sort_by_key(|upstream| upstream.health.latency()),
+    }
+
+    match self.strategy {
+      Strategy::Race if !available.is_empty() => available,
+      _ => {
+        available.extend(unavailable);
+        available
+      }
+    }
+  }
+
+  /// Probes every upstream with a query for the SOA of the zone every
+  /// `interval`, so failed upstreams recover without risking client queries.
+  pub(crate) async fn probe(&self, interval: Duration) {
+    loop {
+      tokio::time::sleep(interval).await;
+
+      join_all(
+        self
+          .upstreams
+          .iter()
+          .map(|upstream| upstream.probe(&self.zone)),
+      )
+      .await;
+    }
+  }
+
+  /// The upstreams as configured, e.g. `tls:1.1.1.1:853/cloudflare-dns.com`.
+  pub(crate) fn labels(&self) -> impl Iterator<Item = &str> {
+    self
+      .upstreams
+      .iter()
+      .map(|upstream| upstream.label.as_str())
+  }
+
+  /// Whether any upstream is in rotation.
+  pub(crate) fn is_healthy(&self) -> bool {
+    let now = Instant::now();
+    self
+      .upstreams
+      .iter()
+      .any(|upstream| upstream.health.available(now))
+  }
+
+  pub(crate) fn stats(&self) -> GroupStats {
+    GroupStats {
+      zone: self.zone.to_string(),
+      strategy: self.strategy,
+      upstreams: self
+        .upstreams
+        .iter()
+        .map(|upstream| UpstreamStats {
+          upstream: upstream.label.clone(),
+          queries: upstream.queries.load(Ordering::Relaxed),
+          failures: upstream.failures.load(Ordering::Relaxed),
+          health: upstream.health.stats(),
+        })
+        .collect(),
+    }
+  }
+}
+
+impl Upstream {
+  fn new(upstream: &UpstreamDns, config: &UpstreamConfig) -> anyhow::Result<Self> {
+    let transport = match upstream {
+      UpstreamDns::TlsHost(host, port, options) => Transport::Host {
+        protocol: Protocol::Tls,
+        host: host.clone(),
+        port: *port,
+        timeout: config.timeout,
+        tls_config: options.client_config(&[])?,
+        // no addresses until the host is resolved
+        resolver: RwLock::new(resolver(vec![], config.timeout)),
+      },
+      UpstreamDns::QuicHost(host, port, options) => Transport::Host {
+        protocol: Protocol::Quic,
+        host: host.clone(),
+        port: *port,
+        timeout: config.timeout,
+        tls_config: options.client_config(&[])?,
+        resolver: RwLock::new(resolver(vec![], config.timeout)),
+      },
+      UpstreamDns::HttpsUrl(url, options) => Transport::Https(DohClient::new(
+        url.clone(),
+        config.timeout,
+        options.client_config(&[b"h2", b"http/1.1"])?,
+      )?),
+      UpstreamDns::H3Url(url, options) => Transport::H3(Doh3Client::with_url(
+        url.clone(),
+        config.timeout,
+        options.config(&[b"h3"])?,
+      )?),
+      UpstreamDns::Tcp(addr) => Transport::Resolver(resolver(
+        vec![name_server(*addr, Protocol::Tcp, None, None)],
+        config.timeout,
+      )),
+      UpstreamDns::Udp(addr) => Transport::Resolver(resolver(
+        vec![name_server(*addr, Protocol::Udp, None, None)],
+        config.timeout,
+      )),
+      UpstreamDns::Tls(addr, domain, options) => Transport::Resolver(resolver(
+        vec![name_server(
+          *addr,
+          Protocol::Tls,
+          Some(domain.clone()),
+          options.client_config(&[])?,
+        )],
+        config.timeout,
+      )),
+      // the HTTPS client of the resolver adds the h2 protocol itself
+      UpstreamDns::Https(addr, domain, options) => Transport::Resolver(resolver(
+        vec![name_server(
+          *addr,
+          Protocol::Https,
+          Some(domain.clone()),
+          options.client_config(&[])?,
+        )],
+        config.timeout,
+      )),
+      // the QUIC client of the resolver adds the doq protocol itself
+      UpstreamDns::Quic(addr, domain, options) => Transport::Resolver(resolver(
+        vec![name_server(
+          *addr,
+          Protocol::Quic,
+          Some(domain.clone()),
+          options.client_config(&[])?,
+        )],
+        config.timeout,
+      )),
+      UpstreamDns::H3(addr, domain, options) => Transport::H3(Doh3Client::with_addr(
+        *addr,
+        domain.clone(),
+        config.timeout,
+        options.config(&[b"h3"])?,
+      )?),
+    };
+
+    Ok(Self {
+      label: upstream.to_string(),
+      transport,
+      addrs: Mutex::new(Vec::new()),
+      health: Health::new(&config.health),
+      queries: AtomicU64::new(0),
+      failures: AtomicU64::new(0),
+    })
+  }
+
+  async fn resolve_host(&self, bootstrap: &Bootstrap) {
+    let Some(host) = self.transport.host() else {
+      return;
+    };
+
+    let mut addrs = match bootstrap.lookup(host).await {
+      Ok(addrs) if !addrs.is_empty() => addrs,
+      Ok(_) => {
+        warn!("Unable to resolve upstream {}: no addresses", self.label);
+        return;
+      }
+      Err(err) => {
+        warn!("Unable to resolve upstream {}: {}", self.label, err);
+        return;
+      }
+    };
+    addrs.sort_unstable();
+
+    if *self.addrs.lock().unwrap() == addrs {
+      return;
+    }
+
+    match self.transport.set_addrs(&addrs) {
+      Ok(()) => {
+        debug!("Upstream {} resolved to {:?}", self.label, addrs);
+        *self.addrs.lock().unwrap() = addrs;
+      }
+      Err(err) => warn!("Unable to reconfigure upstream {}: {}", self.label, err),
+    }
+  }
+
+  async fn lookup(&self, name: &LowerName, rtype: RecordType) -> Result<Lookup, ResolveError> {
+    self.queries.fetch_add(1, Ordering::Relaxed);
+
+    let result = self.timed_lookup(name, rtype).await;
+    if !answered(&result) {
+      self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    result
+  }
+
+  async fn probe(&self, zone: &LowerName) {
+    // the outcome is recorded, that's all a probe is for
+    let _ = self.timed_lookup(zone, RecordType::SOA).await;
+  }
+
+  async fn timed_lookup(
+    &self,
+    name: &LowerName,
+    rtype: RecordType,
+  ) -> Result<Lookup, ResolveError> {
+    let start = Instant::now();
+    let result = self
+      .transport
+      .lookup(name, rtype)
+      .instrument(info_span!("upstream", upstream = %self.label))
+      .await;
+
+    let transition = if answered(&result) {
+      self.health.success(start.elapsed())
+    } else {
+      self.health.failure()
+    };
+
+    match transition {
+      Transition::Opened => warn!("Upstream {} failed, taking it out of rotation", self.label),
+      Transition::Closed => info!("Upstream {} recovered", self.label),
+      Transition::Unchanged => {}
+    }
+
+    result
+  }
+}
+
+impl Transport {
+  fn host(&self) -> Option<&str> {
+    match self {
+      Transport::Resolver(_) => None,
+      Transport::Host { host, .. } => Some(host),
+      Transport::Https(client) => Some(client.host()),
+      Transport::H3(client) => client.host(),
+    }
+  }
+
+  fn set_addrs(&self, addrs: &[IpAddr]) -> anyhow::Result<()> {
+    match self {
+      Transport::Resolver(_) => Ok(()),
+      Transport::Host {
+        protocol,
+        host,
+        port,
+        timeout,
+        tls_config,
+        resolver: current,
+      } => {
+        let name_servers = addrs
+          .iter()
+          .map(|ip| {
+            name_server(
+              SocketAddr::new(*ip, *port),
+              *protocol,
+              Some(host.clone()),
+              tls_config.clone(),
+            )
+          })
+          .collect();
+
+        *current.write().unwrap() = resolver(name_servers, *timeout);
+        Ok(())
+      }
+      Transport::Https(client) => client.set_addrs(addrs),
+      Transport::H3(client) => {
+        client.set_addrs(addrs);
+        Ok(())
+      }
+    }
+  }
+
+  async fn lookup(&self, name: &LowerName, rtype: RecordType) -> Result<Lookup, ResolveError> {
+    match self {
+      Transport::Resolver(resolver) => resolver.lookup(name.clone(), rtype).await,
+      Transport::Host { resolver, .. } => {
+        let resolver = resolver.read().unwrap().clone();
+        resolver.lookup(name.clone(), rtype).await
+      }
+      Transport::Https(client) => client.lookup(name, rtype).await,
+      Transport::H3(client) => client.lookup(name, rtype).await,
+    }
+  }
+}
+
+fn resolver(name_servers: Vec<NameServerConfig>, timeout: Duration) -> TokioAsyncResolver {
+  TokioAsyncResolver::tokio(
+    ResolverConfig::from_parts(None, vec![], NameServerConfigGroup::from(name_servers)),
+    ResolverOpts {
+      timeout,
+      // retries are up to the strategy
+      attempts: 0,
+      // responses are cached in front of the catalog, a second cache
+      // would defeat prefetching and serving stale
+      cache_size: 0,
+      preserve_intermediates: true,
+      ..ResolverOpts::default()
+    },
+  )
+}
+
+/// Whether the upstream answered the query, negative answers included.
+fn answered(result: &Result<Lookup, ResolveError>) -> bool {
+  match result {
+    Ok(_) => true,
+    Err(err) => matches!(
+      err.kind(),
+      ResolveErrorKind::NoRecordsFound {
+        response_code: ResponseCode::NoError | ResponseCode::NXDomain,
+        ..
+      }
+    ),
+  }
+}
+
+fn name_server(
+  socket_addr: SocketAddr,
+  protocol: Protocol,
+  tls_dns_name: Option<String>,
+  tls_config: Option<Arc<ClientConfig>>,
+) -> NameServerConfig {
+  NameServerConfig {
+    socket_addr,
+    protocol,
+    tls_dns_name,
+    trust_negative_responses: true,
+    tls_config: tls_config.map(TlsClientConfig),
+    bind_addr: None,
+  }
+}
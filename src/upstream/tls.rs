@@ -0,0 +1,273 @@
+use std::iter;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use ring::digest::{digest, SHA256};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+
+use crate::certs::{load_certs, load_key};
+
+/// TLS settings of a single upstream, given as query parameters of the
+/// upstream, e.g. `tls://dns.internal?ca=/etc/rdns/ca.pem&sni=false`.
+#[derive(Clone, Default)]
+pub(crate) struct TlsOptions {
+  /// PEM bundle of the CAs to trust instead of the built-in roots.
+  ca: Option<PathBuf>,
+  /// PEM client certificate chain and key for mutual TLS.
+  cert: Option<PathBuf>,
+  key: Option<PathBuf>,
+  /// SHA-256 hashes of subject public key infos, one of which must be in
+  /// the certificate chain.
+  pins: Vec<Vec<u8>>,
+  disable_sni: bool,
+}
+
+impl TlsOptions {
+  /// Applies a single option, returns false if the key is no TLS option.
+  pub(crate) fn set(&mut self, key: &str, value: &str) -> anyhow::Result<bool> {
+    match key {
+      "ca" => self.ca = Some(PathBuf::from(value)),
+      "cert" => self.cert = Some(PathBuf::from(value)),
+      "key" => self.key = Some(PathBuf::from(value)),
+      "pin" => {
+        let hash = value
+          .strip_prefix("sha256/")
+          .ok_or_else(|| anyhow!("Invalid pin {}, expected sha256/<base64>", value))?;
+        // an unescaped + of base64 arrives as space from the query
+        let hash = data_encoding::BASE64.decode(hash.replace(' ', "+").as_bytes())?;
+        if hash.len() != SHA256.output_len {
+          return Err(anyhow!("Invalid pin {}, expected a SHA-256 hash", value));
+        }
+        self.pins.push(hash);
+      }
+      "sni" => self.disable_sni = !value.parse::<bool>()?,
+      _ => return Ok(false),
+    }
+
+    Ok(true)
+  }
+
+  /// Client config honoring the options, `None` if there is nothing to
+  /// customize and the built-in config of the transport can be used.
+  pub(crate) fn client_config(&self, alpn: &[&[u8]]) -> anyhow::Result<Option<Arc<ClientConfig>>> {
+    if self.ca.is_none()
+      && self.cert.is_none()
+      && self.key.is_none()
+      && self.pins.is_empty()
+      && !self.disable_sni
+    {
+      return Ok(None);
+    }
+
+    Ok(Some(Arc::new(self.config(alpn)?)))
+  }
+
+  /// Client config honoring the options, the built-in roots are used
+  /// unless a CA bundle is given.
+  pub(crate) fn config(&self, alpn: &[&[u8]]) -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match &self.ca {
+      Some(path) => {
+        for cert in load_certs(path)? {
+          roots.add(&cert)?;
+        }
+      }
+      None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+          ta.subject,
+          ta.spki,
+          ta.name_constraints,
+        )
+      })),
+    }
+
+    let builder = ClientConfig::builder()
+      .with_safe_defaults()
+      .with_custom_certificate_verifier(Arc::new(PinningVerifier {
+        inner: WebPkiVerifier::new(roots, None),
+        pins: self.pins.clone(),
+      }));
+
+    let mut config = match (&self.cert, &self.key) {
+      (Some(cert), Some(key)) => builder.with_single_cert(load_certs(cert)?, load_key(key)?)?,
+      (None, None) => builder.with_no_client_auth(),
+      _ => return Err(anyhow!("Client certificate and key must be given together")),
+    };
+
+    config.enable_sni = !self.disable_sni;
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Ok(config)
+  }
+}
+
+/// Verifies certificates against the roots and, if any pins are given,
+/// requires the chain to contain one of the pinned public keys.
+struct PinningVerifier {
+  inner: WebPkiVerifier,
+  pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    server_name: &ServerName,
+    scts: &mut dyn Iterator<Item = &[u8]>,
+    ocsp_response: &[u8],
+    now: SystemTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    let verified = self.inner.verify_server_cert(
+      end_entity,
+      intermediates,
+      server_name,
+      scts,
+      ocsp_response,
+      now,
+    )?;
+
+    if self.pins.is_empty() {
+      return Ok(verified);
+    }
+
+    let pinned = iter::once(end_entity)
+      .chain(intermediates)
+      .filter_map(|cert| spki(&cert.0))
+      .any(|spki| {
+        let hash = digest(&SHA256, spki);
+        self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref())
+      });
+
+    if pinned {
+      Ok(verified)
+    } else {
+      Err(rustls::Error::General(
+        "No pinned public key in certificate chain".to_string(),
+      ))
+    }
+  }
+}
+
+/// DER encoded subject public key info of a DER encoded X.509 certificate.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+  let (_, certificate, _) = der_element(cert)?;
+  let (_, mut tbs, _) = der_element(certificate)?;
+
+  // optional explicit version
+  if tbs.first() == Some(&0xa0) {
+    tbs = der_element(tbs)?.2;
+  }
+
+  // serial number, signature algorithm, issuer, validity and subject
+  for _ in 0..5 {
+    tbs = der_element(tbs)?.2;
+  }
+
+  Some(der_element(tbs)?.0)
+}
+
+/// Splits the first DER element off the input, returns the whole element,
+/// its contents and the remaining input.
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+  let (&first, mut rest) = input.get(1..)?.split_first()?;
+
+  let len = if first & 0x80 == 0 {
+    usize::from(first)
+  } else {
+    let count = usize::from(first & 0x7f);
+    if count == 0 || count > 4 || rest.len() < count {
+      return None;
+    }
+    let (bytes, after) = rest.split_at(count);
+    rest = after;
+    bytes
+      .iter()
+      .fold(0, |len, byte| len << 8 | usize::from(*byte))
+  };
+
+  if rest.len() < len {
+    return None;
+  }
+
+  let header = input.len() - rest.len();
+  Some((&input[..header + len], &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Self-signed P-256 certificate, the pin as printed by
+  /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+  /// openssl dgst -sha256 -binary | openssl enc -base64`.
+  const CERT: &str = "\
+    MIIBnDCCAUGgAwIBAgIUaxLrWzO3j8YJ1o3govstmfjLXZYwCgYIKoZIzj0EAwIw\
+    FjEUMBIGA1UEAwwLZG5zLmV4YW1wbGUwIBcNMjYxMDE5MDQxOTI4WhgPMjEyNjA5\
+    MjUwNDE5MjhaMBYxFDASBgNVBAMMC2Rucy5leGFtcGxlMFkwEwYHKoZIzj0CAQYI\
+    KoZIzj0DAQcDQgAEVE7yPQ3FqfOPp+gCNlSHMBrlxrKuQ765aJptGCi2fNivdQje\
+    CJC3oIuymj1xpjeXCqdeMx9JvhkQk7lZ4vFisKNrMGkwHQYDVR0OBBYEFGXYA8Zh\
+    k1jcSdOcxAj3gvlAVQy/MB8GA1UdIwQYMBaAFGXYA8Zhk1jcSdOcxAj3gvlAVQy/\
+    MA8GA1UdEwEB/wQFMAMBAf8wFgYDVR0RBA8wDYILZG5zLmV4YW1wbGUwCgYIKoZI\
+    zj0EAwIDSQAwRgIhAJV/uq5Io89tL6KlaJXHvaF/WvfDi35dQxH8OeRFuWVDAiEA\
+    7IiWDQCtZIvpoy6AvUSEWseVz8nnjFVEvW30R1gS9l4=";
+  const PIN: &str = "BM8Z4sn5QfyA5eZeLPSrk8Nou66MX9CJI8ayST4Ec9g=";
+
+  fn cert() -> Vec<u8> {
+    data_encoding::BASE64.decode(CERT.as_bytes()).unwrap()
+  }
+
+  #[test]
+  fn hashes_spki_of_certificate() {
+    let cert = cert();
+    let spki = spki(&cert).unwrap();
+
+    // SEQUENCE of the EC public key algorithm and the key
+    assert_eq!(&spki[..4], &[0x30, 0x59, 0x30, 0x13]);
+    assert_eq!(
+      data_encoding::BASE64.encode(digest(&SHA256, spki).as_ref()),
+      PIN
+    );
+  }
+
+  #[test]
+  fn rejects_truncated_certificate() {
+    let cert = cert();
+    for len in [0, 1, 4, 100, cert.len() - 1] {
+      assert!(spki(&cert[..len]).is_none(), "parsed {} bytes", len);
+    }
+  }
+
+  #[test]
+  fn splits_der_elements() {
+    // short form length
+    let (element, contents, rest) = der_element(&[0x04, 0x02, 0xaa, 0xbb, 0xcc]).unwrap();
+    assert_eq!(element, &[0x04, 0x02, 0xaa, 0xbb]);
+    assert_eq!(contents, &[0xaa, 0xbb]);
+    assert_eq!(rest, &[0xcc]);
+
+    // long form length
+    let mut input = vec![0x04, 0x81, 0x80];
+    input.extend_from_slice(&[0; 0x80]);
+    let (element, contents, rest) = der_element(&input).unwrap();
+    assert_eq!(element.len(), 0x83);
+    assert_eq!(contents.len(), 0x80);
+    assert!(rest.is_empty());
+  }
+
+  #[test]
+  fn rejects_invalid_der_lengths() {
+    // contents shorter than the length
+    assert!(der_element(&[0x04, 0x03, 0xaa]).is_none());
+    // indefinite length, not allowed in DER
+    assert!(der_element(&[0x30, 0x80, 0x00, 0x00]).is_none());
+    // length of more than 4 bytes
+    assert!(der_element(&[0x04, 0x85, 0, 0, 0, 0, 1, 0]).is_none());
+    // missing length bytes
+    assert!(der_element(&[0x04, 0x82, 0x01]).is_none());
+    assert!(der_element(&[0x04]).is_none());
+  }
+}
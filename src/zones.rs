@@ -0,0 +1,128 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::task::JoinHandle;
+use tracing::info;
+use trust_dns_server::authority::Catalog;
+use trust_dns_server::proto::rr::LowerName;
+use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+
+use crate::args::Args;
+use crate::authority::forward::UpstreamAuthority;
+use crate::authority::netbox::{NetboxClient, NetboxIpv4Authority};
+use crate::authority::traced::TracedAuthority;
+use crate::upstream::bootstrap::Bootstrap;
+use crate::upstream::UpstreamGroup;
+
+/// The authorities answering queries, the forwarding zones and NetBox
+/// reverse lookups, built as a whole from the configuration.
+pub(crate) struct Zones {
+  catalog: Catalog,
+  upstreams: Vec<Arc<UpstreamGroup>>,
+  netbox: Option<Arc<NetboxClient>>,
+  /// Host refreshes and health probes of the upstreams.
+  tasks: Vec<JoinHandle<()>>,
+}
+
+impl Zones {
+  pub(crate) async fn build(args: &Args) -> anyhow::Result<Self> {
+    let mut catalog = Catalog::new();
+    let mut upstreams = Vec::new();
+    let mut tasks = Vec::new();
+    let mut netbox = None;
+
+    let upstream_config = args.upstream_config();
+    let bootstrap = Arc::new(Bootstrap::new(&args.bootstrap_resolver));
+
+    for forwarding in &args.forwarding {
+      let group = Arc::new(UpstreamGroup::new(
+        forwarding.name.clone(),
+        &forwarding.upstreams,
+        &upstream_config,
+      )?);
+
+      if group.has_hosts() {
+        group.resolve_hosts(&bootstrap).await;
+
+        if args.bootstrap_refresh > 0 {
+          let group = group.clone();
+          let bootstrap = bootstrap.clone();
+          let interval = Duration::from_secs(args.bootstrap_refresh);
+          tasks.push(tokio::spawn(async move {
+            group.refresh_hosts(&bootstrap, interval).await
+          }));
+        }
+      }
+
+      if args.upstream_health_interval > 0 {
+        let group = group.clone();
+        let interval = Duration::from_secs(args.upstream_health_interval);
+        tasks.push(tokio::spawn(async move { group.probe(interval).await }));
+      }
+
+      let zone = group.zone().clone();
+      catalog.upsert(
+        zone.clone(),
+        Box::new(TracedAuthority::new(
+          "forward",
+          zone,
+          Box::new(UpstreamAuthority::new(group.clone())),
+        )),
+      );
+      upstreams.push(group);
+    }
+
+    if let (Some(url), Some(token)) = (&args.reverse_dns_netbox_url, &args.reverse_dns_netbox_token)
+    {
+      info!("Configuring netbox");
+      let client = Arc::new(NetboxClient::new(url.clone(), token.clone()));
+      let zone = LowerName::from_str("in-addr.arpa.")?;
+      catalog.upsert(
+        zone.clone(),
+        Box::new(TracedAuthority::new(
+          "netbox",
+          zone,
+          Box::new(NetboxIpv4Authority::new(client.clone())),
+        )),
+      );
+      netbox = Some(client);
+    }
+
+    Ok(Self {
+      catalog,
+      upstreams,
+      netbox,
+      tasks,
+    })
+  }
+
+  pub(crate) fn upstreams(&self) -> &[Arc<UpstreamGroup>] {
+    &self.upstreams
+  }
+
+  /// Client of the NetBox reverse lookups, if configured.
+  pub(crate) fn netbox(&self) -> Option<&NetboxClient> {
+    self.netbox.as_deref()
+  }
+}
+
+impl Drop for Zones {
+  fn drop(&mut self) {
+    for task in &self.tasks {
+      task.abort();
+    }
+  }
+}
+
+#[async_trait]
+impl RequestHandler for Zones {
+  async fn handle_request<R: ResponseHandler>(
+    &self,
+    request: &Request,
+    response_handle: R,
+  ) -> ResponseInfo {
+    self.catalog.handle_request(request, response_handle).await
+  }
+}